@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::crate_name;
+use platform_dirs::AppDirs;
+
+use crate::password::Secret;
+
+/// How long clients wait for the agent before giving up on it.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often the agent forgets expired passwords.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+type Store = Arc<Mutex<HashMap<String, (Secret, Instant)>>>;
+
+/// Where the agent listens: `$SENDMAIL_AGENT_SOCK`, or `sendmail/agent.sock`
+/// in `$XDG_RUNTIME_DIR` (falling back to the cache directory).
+pub fn socket_path() -> PathBuf {
+    if let Some(path) = env::var_os("SENDMAIL_AGENT_SOCK") {
+        return PathBuf::from(path);
+    }
+
+    let directory = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) => PathBuf::from(runtime).join(crate_name!()),
+        None => AppDirs::new(Some(crate_name!()), false).unwrap().cache_dir,
+    };
+
+    directory.join("agent.sock")
+}
+
+/// Runs the agent in the foreground, keeping passwords for `ttl` after they
+/// were handed to it.
+pub fn run(ttl: Duration) {
+    let path = socket_path();
+
+    if UnixStream::connect(&path).is_ok() {
+        panic!("{}: An agent is already running.", path.display())
+    }
+
+    // Left behind by an agent that didn't exit cleanly.
+    let _ = fs::remove_file(&path);
+
+    let directory = path.parent().unwrap();
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(directory)
+        .unwrap_or_else(|e| panic!("{}: Couldn't create directory: {e}", directory.display()));
+
+    let listener = UnixListener::bind(&path)
+        .unwrap_or_else(|e| panic!("{}: Couldn't listen on socket: {e}", path.display()));
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+        .unwrap_or_else(|e| panic!("{}: Couldn't restrict socket permissions: {e}", path.display()));
+
+    println!("Agent listening on {}, keeping passwords for {}s.", path.display(), ttl.as_secs());
+
+    let store: Store = Arc::default();
+
+    let sweeper = Arc::clone(&store);
+    thread::spawn(move || loop {
+        thread::sleep(SWEEP_INTERVAL);
+        sweeper.lock().unwrap().retain(|_, (_, expires)| *expires > Instant::now());
+    });
+
+    for stream in listener.incoming().flatten() {
+        // A misbehaving client shouldn't take the agent down.
+        let _ = handle(stream, &store, ttl);
+    }
+}
+
+/// Handles a single request: `get`, `put` or `forget`, followed by the account
+/// and for `put` the password, separated by tabs. The answer is `ok`, followed
+/// by the password for `get`, or `none`.
+fn handle(stream: UnixStream, store: &Store, ttl: Duration) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut request = Secret::new(String::new());
+    BufReader::new(&stream).read_line(request.as_mut_string())?;
+
+    let mut parts = request.expose().trim_end_matches(['\r', '\n']).splitn(3, '\t');
+    let command = parts.next().unwrap_or_default();
+    let account = parts.next().unwrap_or_default().to_string();
+
+    let mut store = store.lock().unwrap();
+
+    let response = match (command, parts.next()) {
+        ("get", None) => match store.get(&account) {
+            Some((password, expires)) if *expires > Instant::now() => Secret::new(format!("ok\t{}\n", password.expose())),
+            _ => Secret::new("none\n".to_string()),
+        },
+        ("put", Some(password)) => {
+            store.insert(account, (Secret::new(password.to_string()), Instant::now() + ttl));
+            Secret::new("ok\n".to_string())
+        }
+        ("forget", None) => {
+            store.remove(&account);
+            Secret::new("ok\n".to_string())
+        }
+        _ => Secret::new("error\n".to_string()),
+    };
+
+    (&stream).write_all(response.expose().as_bytes())
+}
+
+/// Asks a running agent for the password of `account`.
+pub fn get(account: &str) -> Option<Secret> {
+    let response = request(&format!("get\t{}\n", account))?;
+    let password = response.expose().strip_prefix("ok\t")?.trim_end_matches('\n');
+
+    Some(Secret::new(password.to_string()))
+}
+
+/// Hands the password of `account` to a running agent, if there is one.
+pub fn put(account: &str, password: &Secret) {
+    let request_line = Secret::new(format!("put\t{}\t{}\n", account, password.expose()));
+    let _ = request(request_line.expose());
+}
+
+/// Makes a running agent drop the password of `account`, such as after the
+/// server rejected it.
+pub fn forget(account: &str) {
+    let _ = request(&format!("forget\t{}\n", account));
+}
+
+fn request(line: &str) -> Option<Secret> {
+    let stream = UnixStream::connect(socket_path()).ok()?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT)).ok()?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT)).ok()?;
+
+    (&stream).write_all(line.as_bytes()).ok()?;
+
+    let mut response = Secret::new(String::new());
+    BufReader::new(&stream).read_line(response.as_mut_string()).ok()?;
+
+    Some(response)
+}
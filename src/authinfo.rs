@@ -0,0 +1,256 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::config::ServerConfig;
+use crate::password::Secret;
+
+/// Files to search, relative to the home directory. The first one with a
+/// matching entry wins, like Emacs' `auth-sources`.
+const FILES: [&str; 4] = [".authinfo.gpg", ".authinfo", ".netrc.gpg", ".netrc"];
+
+#[derive(Default)]
+struct Entry {
+    /// `None` for the `default` entry, which matches any machine.
+    machine: Option<String>,
+    login: Option<String>,
+    port: Option<String>,
+    password: Option<String>,
+}
+
+/// Looks up the password for `server` in `~/.authinfo`, `~/.netrc` or their
+/// GPG-encrypted variants, matching on hostname, username and port.
+pub fn lookup(server: &ServerConfig) -> Option<Secret> {
+    files()
+        .into_iter()
+        .filter(|path| path.exists())
+        .find_map(|path| {
+            let contents = read(&path);
+            find_password(contents.expose(), server)
+        })
+}
+
+/// The files searched by `lookup`, most specific first.
+pub fn files() -> Vec<PathBuf> {
+    let home = env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    FILES.iter().map(|file| home.join(file)).collect()
+}
+
+fn read(path: &Path) -> Secret {
+    if path.extension().is_some_and(|extension| extension == "gpg") {
+        return decrypt(path);
+    }
+
+    let contents = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("{}: Couldn't read file: {e}", path.display()));
+
+    Secret::new(contents)
+}
+
+/// Decrypts a file through gpg, which asks gpg-agent for the key.
+fn decrypt(path: &Path) -> Secret {
+    let output = Command::new("gpg")
+        .args(["--quiet", "--decrypt"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap_or_else(|e| panic!("{}: Couldn't run gpg: {e}", path.display()));
+
+    let contents = Secret::new(String::from_utf8(output.stdout)
+        .unwrap_or_else(|_| panic!("{}: Decrypted file isn't valid UTF-8.", path.display())));
+
+    if !output.status.success() {
+        panic!("{}: Couldn't decrypt file ({}).", path.display(), output.status)
+    }
+
+    contents
+}
+
+fn find_password(contents: &str, server: &ServerConfig) -> Option<Secret> {
+    let entries = parse(contents);
+    let port = server.port.to_string();
+
+    // An entry without a password is no use, even if it matches.
+    let matches = |entry: &&Entry| {
+        entry.password.is_some()
+            && entry.login.as_ref().is_none_or(|login| Some(login) == server.username.as_ref())
+            && entry.port.as_ref().is_none_or(|entry_port| *entry_port == port || service_port(entry_port) == Some(server.port))
+    };
+
+    // The `default` entry only applies when no machine matches, wherever it is in the file.
+    let entry = entries.iter()
+        .filter(|entry| entry.machine.as_ref().is_some_and(|machine| machine.eq_ignore_ascii_case(&server.hostname)))
+        .find(matches)
+        .or_else(|| entries.iter().filter(|entry| entry.machine.is_none()).find(matches))?;
+
+    entry.password.clone().map(Secret::new)
+}
+
+/// Ports can also be written as their service name in authinfo files.
+fn service_port(name: &str) -> Option<u16> {
+    match name {
+        "smtp" => Some(25),
+        "submission" => Some(587),
+        "smtps" | "submissions" => Some(465),
+        _ => None,
+    }
+}
+
+fn parse(contents: &str) -> Vec<Entry> {
+    let mut tokens = tokenize(contents).into_iter();
+    let mut entries: Vec<Entry> = Vec::new();
+
+    while let Some(token) = tokens.next() {
+        match token.as_str() {
+            "machine" => entries.push(Entry { machine: tokens.next(), ..Entry::default() }),
+            "default" => entries.push(Entry::default()),
+            "login" | "user" => set(&mut entries, tokens.next(), |entry| &mut entry.login),
+            "password" => set(&mut entries, tokens.next(), |entry| &mut entry.password),
+            "port" => set(&mut entries, tokens.next(), |entry| &mut entry.port),
+            // Macros (and the `account` field) aren't about logging in.
+            _ => { tokens.next(); }
+        }
+    }
+
+    entries
+}
+
+fn set(entries: &mut [Entry], value: Option<String>, field: impl FnOnce(&mut Entry) -> &mut Option<String>) {
+    if let Some(entry) = entries.last_mut() {
+        *field(entry) = value;
+    }
+}
+
+/// Splits on whitespace, keeping double-quoted strings together and
+/// dropping comment lines and `macdef` bodies.
+fn tokenize(contents: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut lines = contents.lines();
+
+    while let Some(line) = lines.next() {
+        if line.trim_start().starts_with('#') { continue }
+        let mut chars = line.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if c.is_whitespace() { continue }
+
+            let mut token = String::new();
+
+            if c == '"' {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => token.extend(chars.next()),
+                        c => token.push(c),
+                    }
+                }
+            } else {
+                token.push(c);
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    token.push(c);
+                }
+            }
+
+            tokens.push(token);
+        }
+
+        // A macro runs until the next empty line.
+        if tokens.len() >= 2 && tokens[tokens.len() - 2] == "macdef" {
+            tokens.truncate(tokens.len() - 2);
+            for line in lines.by_ref() {
+                if line.trim().is_empty() { break }
+            }
+        }
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(hostname: &str, port: u16, username: &str) -> ServerConfig {
+        toml::from_str(&format!("hostname = \"{}\"\nport = {}\nusername = \"{}\"", hostname, port, username)).unwrap()
+    }
+
+    fn password(contents: &str, server: &ServerConfig) -> Option<String> {
+        find_password(contents, server).map(|secret| secret.expose().to_string())
+    }
+
+    #[test]
+    fn matches_machine_login_and_port() {
+        let contents = "
+            machine smtp.example.com login alice port 465 password wrong-port
+            machine smtp.example.com login bob port 587 password wrong-login
+            machine SMTP.example.com login alice port 587 password right
+        ";
+
+        assert_eq!(password(contents, &server("smtp.example.com", 587, "alice")).as_deref(), Some("right"));
+        assert_eq!(password(contents, &server("imap.example.com", 587, "alice")), None);
+    }
+
+    #[test]
+    fn treats_missing_login_and_port_as_wildcards() {
+        let contents = "machine smtp.example.com password anything";
+
+        assert_eq!(password(contents, &server("smtp.example.com", 465, "alice")).as_deref(), Some("anything"));
+        assert_eq!(password(contents, &server("smtp.example.com", 587, "bob")).as_deref(), Some("anything"));
+    }
+
+    #[test]
+    fn understands_service_names() {
+        let contents = "
+            machine smtp.example.com port smtps password implicit
+            machine smtp.example.com port submission password starttls
+        ";
+
+        assert_eq!(password(contents, &server("smtp.example.com", 465, "alice")).as_deref(), Some("implicit"));
+        assert_eq!(password(contents, &server("smtp.example.com", 587, "alice")).as_deref(), Some("starttls"));
+        assert_eq!(password(contents, &server("smtp.example.com", 25, "alice")), None);
+    }
+
+    #[test]
+    fn falls_back_to_default_wherever_it_is() {
+        let contents = "
+            default login alice password fallback
+            machine smtp.example.com login alice password specific
+        ";
+
+        assert_eq!(password(contents, &server("smtp.example.com", 587, "alice")).as_deref(), Some("specific"));
+        assert_eq!(password(contents, &server("other.example.com", 587, "alice")).as_deref(), Some("fallback"));
+        assert_eq!(password(contents, &server("other.example.com", 587, "bob")), None);
+    }
+
+    #[test]
+    fn skips_entries_without_a_password() {
+        let contents = "
+            machine smtp.example.com login alice
+            machine smtp.example.com login alice password second
+        ";
+
+        assert_eq!(password(contents, &server("smtp.example.com", 587, "alice")).as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn reads_quoted_passwords() {
+        let contents = r#"machine smtp.example.com login alice password "correct horse \"battery\" staple""#;
+
+        assert_eq!(password(contents, &server("smtp.example.com", 587, "alice")).as_deref(), Some("correct horse \"battery\" staple"));
+    }
+
+    #[test]
+    fn skips_comments_and_macros() {
+        let contents = "
+# machine smtp.example.com login alice password commented
+macdef init
+machine smtp.example.com login alice password in-macro
+
+machine smtp.example.com login alice password real
+";
+
+        assert_eq!(password(contents, &server("smtp.example.com", 587, "alice")).as_deref(), Some("real"));
+    }
+}
@@ -0,0 +1,242 @@
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use std::process;
+
+use lettre::Address;
+use toml_edit::{ImDocument, Item, Table};
+
+use crate::config::{self, field_names, Config, Identity, Location, ServerConfig, Settings};
+
+struct Problem {
+    line: usize,
+    message: String,
+}
+
+enum Kind {
+    Settings,
+    Defaults,
+    Account(String),
+}
+
+/// Checks the config of `account`, or every config file when no account is
+/// given, and reports all problems found. Exits with status 1 if there were any.
+pub fn check_config(settings: &Settings, account: Option<String>) {
+    let mut files = Vec::new();
+    let mut only = None;
+
+    match account.map(|account| settings.resolve_account(Some(account))) {
+        Some(account) => match settings.locate(&account) {
+            Ok(Location::File(path)) => files.push((path, Kind::Account(account))),
+            Ok(Location::Environment(_)) => {
+                println!("{}: Defined by environment variables only, nothing to check.", account);
+                return;
+            }
+            Ok(Location::Inline(path, _)) => {
+                files.push((path, Kind::Settings));
+                only = Some(account);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1)
+            }
+        },
+        None => {
+            if settings.path.exists() { files.push((settings.path.clone(), Kind::Settings)) }
+
+            if let Some(directory) = settings.accounts_dir() {
+                let defaults = directory.join(config::DEFAULTS_FILE);
+                if defaults.exists() { files.push((defaults, Kind::Defaults)) }
+
+                for account in settings.account_names() {
+                    if let Ok(Location::File(path)) = settings.locate(&account) {
+                        files.push((path, Kind::Account(account)));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut failed = false;
+
+    for (file, kind) in files {
+        let problems = check_file(settings, &file, kind, only.as_deref());
+        failed |= !problems.is_empty();
+
+        if problems.is_empty() {
+            println!("{}: OK", file.display());
+        }
+
+        for problem in problems {
+            eprintln!("{}:{}: {}", file.display(), problem.line, problem.message);
+        }
+    }
+
+    if failed { process::exit(1) }
+}
+
+fn check_file(settings: &Settings, path: &Path, kind: Kind, only: Option<&str>) -> Vec<Problem> {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => return vec![Problem { line: 0, message: format!("Couldn't read file: {e}") }],
+    };
+
+    let document = match ImDocument::parse(source.as_str()) {
+        Ok(document) => document,
+        Err(e) => return vec![problem(&source, e.span(), e.message().trim())],
+    };
+
+    let mut problems = Vec::new();
+
+    match kind {
+        Kind::Settings => check_settings(settings, &source, &document, only, &mut problems),
+        // The defaults only have to make sense once they're merged into an account.
+        Kind::Defaults => check_account(settings, &source, &document, None, "", &mut problems),
+        Kind::Account(account) => check_account(settings, &source, &document, Some(&account), "", &mut problems),
+    }
+
+    problems.sort_by_key(|problem| problem.line);
+    problems
+}
+
+fn check_settings(settings: &Settings, source: &str, document: &Table, only: Option<&str>, problems: &mut Vec<Problem>) {
+    let accounts = document.get("accounts").and_then(Item::as_table);
+
+    if only.is_none() {
+        check_keys(source, document, field_names::<Settings>(), "", problems);
+
+        if let Err(e) = toml::from_str::<Settings>(source) {
+            return problems.push(problem(source, e.span(), e.message()));
+        }
+
+        let names = settings.account_names();
+        let targets = settings.default_account.iter().map(|account| ("default_account", account))
+            .chain(settings.aliases.iter().map(|(alias, account)| (alias.as_str(), account)));
+
+        for (key, account) in targets {
+            if names.contains(account) { continue }
+
+            let span = document.get(key)
+                .or_else(|| document.get("aliases")?.as_table()?.get(key))
+                .and_then(Item::span);
+
+            problems.push(problem(source, span, &format!("`{}` refers to unknown account `{}`.", key, account)));
+        }
+
+        if let Some(defaults) = document.get("defaults").and_then(Item::as_table) {
+            check_account(settings, source, defaults, None, "defaults", problems);
+        }
+    }
+
+    for (name, account) in accounts.iter().flat_map(|accounts| accounts.iter()) {
+        if only.is_some_and(|only| only != name) { continue }
+
+        if let Some(account) = account.as_table() {
+            check_account(settings, source, account, Some(name), &format!("accounts.{}", name), problems);
+        }
+    }
+}
+
+/// Checks the keys and values of an account, given as the file or table
+/// `table`. When `account` is given, the effective config is checked as well.
+fn check_account(settings: &Settings, source: &str, table: &Table, account: Option<&str>, section: &str, problems: &mut Vec<Problem>) {
+    let join = |key: &str| if section.is_empty() { key.to_string() } else { format!("{}.{}", section, key) };
+    let prefix = if section.is_empty() { String::new() } else { format!("[{}]: ", section) };
+
+    let known: Vec<&str> = field_names::<Config>().iter().copied().chain([config::EXTENDS_KEY]).collect();
+    check_keys(source, table, &known, section, problems);
+
+    for server in config::SERVER_SECTIONS {
+        if let Some(Item::Table(server_table)) = table.get(server) {
+            check_keys(source, server_table, field_names::<ServerConfig>(), &join(server), problems);
+        }
+    }
+
+    check_address(source, table, "email", &prefix, problems);
+    check_address(source, table, "reply_to", &prefix, problems);
+
+    if let Some(identities) = table.get("identities").and_then(Item::as_array_of_tables) {
+        for (index, identity) in identities.iter().enumerate() {
+            let section = join(&format!("identities.{}", index));
+            check_keys(source, identity, field_names::<Identity>(), &section, problems);
+
+            let prefix = format!("[{}]: ", section);
+            check_address(source, identity, "email", &prefix, problems);
+            check_address(source, identity, "reply_to", &prefix, problems);
+        }
+    }
+
+    let Some(account) = account else { return };
+    let table_span = table.span();
+
+    let merged = match settings.get_merged_table(account) {
+        Ok(merged) => merged,
+        Err(e) => {
+            let span = table.get(config::EXTENDS_KEY).and_then(Item::span).or(table_span);
+            return problems.push(problem(source, span, &e));
+        }
+    };
+
+    // Accounts that are extended only have to be complete once they are, like the defaults.
+    let is_base = || !settings.extended_by(account).is_empty();
+
+    match config::from_table(merged) {
+        Ok(_) | Err(_) if is_base() => {}
+        Ok(config) => {
+            if let Err(e) = config.validate() {
+                let span = table.get("smtp").and_then(Item::span).or(table_span);
+                problems.push(problem(source, span, &format!("{}{}", prefix, e)));
+            }
+        }
+        Err(e) => problems.push(problem(source, error_span(table, &e).or(table_span), &format!("{}{}", prefix, e))),
+    }
+}
+
+fn check_address(source: &str, table: &Table, key: &str, prefix: &str, problems: &mut Vec<Problem>) {
+    let Some(value) = table.get(key).and_then(Item::as_value) else { return };
+    let Some(address) = value.as_str() else { return };
+
+    if address.parse::<Address>().is_err() {
+        problems.push(problem(source, value.span(), &format!("{}Malformed address in `{}`: {}", prefix, key, address)));
+    }
+}
+
+/// Finds the key a deserialization error like "invalid type ... in `smtp.port`"
+/// is about. Falls back to the closest table when the key itself is inherited.
+fn error_span(table: &Table, error: &str) -> Option<Range<usize>> {
+    let path = error.rsplit_once(" in `")?.1.strip_suffix('`')?;
+    let mut current = table;
+    let mut span = None;
+
+    for key in path.split('.') {
+        let (key, item) = current.get_key_value(key)?;
+        span = key.span().or_else(|| item.span()).or(span);
+
+        match item.as_table() {
+            Some(table) => current = table,
+            None => break,
+        }
+    }
+
+    span
+}
+
+fn check_keys(source: &str, table: &Table, known: &[&str], section: &str, problems: &mut Vec<Problem>) {
+    for (key, _) in table.iter() {
+        if known.contains(&key) { continue }
+
+        let (key, _) = table.get_key_value(key).unwrap();
+        let name = if section.is_empty() { key.get().to_string() } else { format!("{}.{}", section, key.get()) };
+
+        problems.push(problem(source, key.span(), &format!("Unknown key `{}`, expected one of: {}", name, known.join(", "))));
+    }
+}
+
+fn problem(source: &str, span: Option<Range<usize>>, message: &str) -> Problem {
+    let line = span.map(|span| line_number(source, span.start)).unwrap_or(1);
+    Problem { line, message: message.to_string() }
+}
+
+fn line_number(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
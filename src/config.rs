@@ -0,0 +1,1022 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::crate_name;
+use platform_dirs::AppDirs;
+use serde::de::{self, DeserializeOwned, Deserializer, Visitor};
+use serde::Deserialize;
+use toml::{Table, Value};
+
+use crate::time;
+use crate::tls;
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub name: String,
+    pub email: String,
+
+    /// Where replies should go, if not to `email`.
+    pub reply_to: Option<String>,
+
+    /// Appended to the body of every email, below a `-- ` line.
+    pub signature: Option<String>,
+
+    /// Wait this many seconds before sending, to allow cancelling.
+    pub undo_seconds: Option<u64>,
+
+    /// Send at most this many emails in a minute, waiting when there are more.
+    pub max_per_minute: Option<u32>,
+
+    /// Send at most this many emails in 24 hours, refusing (and queueing) the rest.
+    pub max_per_day: Option<u32>,
+
+    /// Other addresses this account may send as, selected with `--from`.
+    #[serde(default)]
+    pub identities: Vec<Identity>,
+
+    /// How to send: through `smtp`, or by handing the message to a local MTA.
+    #[serde(default)]
+    pub transport: TransportKind,
+
+    /// The sendmail-compatible binary to use with `transport = "sendmail"`,
+    /// such as `/usr/bin/msmtp`. Defaults to `sendmail` on the `PATH`.
+    pub sendmail_command: Option<String>,
+
+    /// Arguments to pass to `sendmail_command` before the usual ones, such as
+    /// `["-a", "work"]` to pick an msmtp account.
+    #[serde(default)]
+    pub sendmail_args: Vec<String>,
+
+    /// Required unless `transport = "sendmail"`.
+    pub smtp: Option<ServerConfig>,
+
+    /// Not used for sending, so it may be left out.
+    #[allow(unused)]
+    pub imap: Option<ServerConfig>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    /// Connect to the SMTP server in `[smtp]`.
+    #[default]
+    Smtp,
+    /// Pipe the message into a local `sendmail` binary, such as Postfix or msmtp.
+    Sendmail,
+}
+
+/// An address to send as, such as `support@` or `noreply@`, through the
+/// same SMTP login as the account.
+#[derive(Deserialize, Clone)]
+pub struct Identity {
+    /// Defaults to the name of the account.
+    pub name: Option<String>,
+    pub email: String,
+    pub reply_to: Option<String>,
+    pub signature: Option<String>,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.undo_seconds.is_some_and(|seconds| Duration::from_secs(seconds) > time::MAX_DURATION) {
+            return Err("`undo_seconds` can't be more than 100 years.".to_string())
+        }
+
+        if self.max_per_minute == Some(0) { return Err("`max_per_minute` must be at least 1.".to_string()) }
+        if self.max_per_day == Some(0) { return Err("`max_per_day` must be at least 1.".to_string()) }
+
+        // It's run directly, not through a shell, so `msmtp -a work` would be taken as one file name.
+        if let Some(command) = self.sendmail_command.as_ref().filter(|command| command.contains(char::is_whitespace)) {
+            if !Path::new(command).exists() {
+                return Err(format!("`sendmail_command` should be just the binary, put arguments in `sendmail_args`, like `sendmail_args = [\"-a\", \"work\"]`. Got `{}`.", command))
+            }
+        }
+
+        match (self.transport, &self.smtp) {
+            (TransportKind::Smtp, None) => Err("Missing [smtp] section. Add one, or set `transport = \"sendmail\"`.".to_string()),
+            (_, Some(smtp)) => smtp.validate().map_err(|e| format!("[smtp]: {}", e)),
+            (TransportKind::Sendmail, None) => Ok(()),
+        }
+    }
+
+    /// Picks the identity to send as. `from` can be the address of an
+    /// identity, or just the part before the `@`. Defaults to the account itself.
+    pub fn identity(&self, from: Option<&str>) -> Result<Identity, String> {
+        let own = Identity {
+            name: Some(self.name.clone()),
+            email: self.email.clone(),
+            reply_to: self.reply_to.clone(),
+            signature: self.signature.clone(),
+        };
+
+        let Some(from) = from else { return Ok(own) };
+
+        let matches = |identity: &Identity| {
+            let local_part = identity.email.split('@').next().unwrap_or_default();
+            identity.email.eq_ignore_ascii_case(from) || local_part.eq_ignore_ascii_case(from)
+        };
+
+        let candidates: Vec<&Identity> = self.identities.iter().filter(|identity| matches(identity)).collect();
+
+        match candidates.as_slice() {
+            [identity] => Ok(Identity {
+                name: identity.name.clone().or(Some(self.name.clone())),
+                ..(*identity).clone()
+            }),
+            [] if matches(&own) => Ok(own),
+            [] => {
+                let mut known: Vec<&str> = vec![&self.email];
+                known.extend(self.identities.iter().map(|identity| identity.email.as_str()));
+                Err(format!("This account can't send as `{}`. Known addresses: {}.", from, known.join(", ")))
+            }
+            _ => Err(format!("`{}` matches more than one identity, use the full address.", from)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ServerConfig {
+    pub hostname: String,
+    pub port: u16,
+
+    /// Who to log in as. Without a username, no authentication is attempted,
+    /// which is what trusted relays and a local Postfix want.
+    pub username: Option<String>,
+
+    /// Command to run to get the password, such as `pass mail/school`.
+    /// The first line of its output is used.
+    pub password_command: Option<String>,
+
+    /// Where to look up the password instead of `password_command`.
+    pub password_source: Option<PasswordSource>,
+
+    /// How to secure the connection. When omitted, implicit TLS is used on
+    /// port 465 and STARTTLS everywhere else.
+    pub tls: Option<TlsMode>,
+
+    /// PEM bundle with extra CA certificates to trust, for servers with a private CA.
+    pub ca_file: Option<String>,
+
+    /// SHA-256 fingerprint of the server certificate, as printed by
+    /// `openssl x509 -noout -fingerprint -sha256`. Any other certificate is rejected.
+    /// This is checked on top of the usual validation, not instead of it.
+    pub pin_sha256: Option<String>,
+
+    /// PEM certificate to present to the server (mutual TLS), with `client_key`.
+    /// Not supported yet, so setting it is an error rather than silently ignored.
+    pub client_cert: Option<String>,
+
+    /// PEM private key that goes with `client_cert`.
+    pub client_key: Option<String>,
+
+    /// Accept any certificate, including self-signed and expired ones. Only
+    /// meant for lab setups, as it makes the connection trivial to intercept.
+    #[serde(default)]
+    pub danger_accept_invalid_certs: bool,
+
+    /// Give up on connecting to or hearing back from the server after this many seconds.
+    pub timeout_secs: Option<u64>,
+
+    /// How many times to retry after a transient failure, such as a dropped
+    /// connection or a 4xx reply. Permanent (5xx) failures are never retried.
+    #[serde(default)]
+    pub retries: u32,
+
+    /// Seconds to wait before the first retry, doubled for every retry after that.
+    #[serde(default = "default_retry_backoff")]
+    pub retry_backoff: f64,
+
+    /// How to authenticate, defaults to a username and password.
+    #[serde(default)]
+    pub auth: AuthMethod,
+
+    /// SASL mechanisms to try, in order. Defaults to `["plain", "login"]`, or
+    /// `["xoauth2"]` when `auth = "oauth2"`.
+    pub auth_mechanisms: Option<Vec<AuthMechanism>>,
+
+    /// OAuth2 client ID, required when `auth = "oauth2"`.
+    pub client_id: Option<String>,
+
+    /// OAuth2 client secret, only needed for providers that require one.
+    pub client_secret: Option<String>,
+
+    /// Where to exchange the refresh token for an access token, such as
+    /// `https://oauth2.googleapis.com/token`.
+    pub token_endpoint: Option<String>,
+}
+
+fn default_retry_backoff() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    /// Log in with the password.
+    #[default]
+    Password,
+    /// Log in via XOAUTH2. The password sources provide the refresh token.
+    OAuth2,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordSource {
+    /// The Secret Service, keyed by account name. Set with `sendmail password set`.
+    Keyring,
+    /// `~/.authinfo`, `~/.netrc` or their `.gpg` variants, matched on
+    /// hostname, username and port.
+    #[serde(alias = "authinfo")]
+    Netrc,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMechanism {
+    Plain,
+    Login,
+    Xoauth2,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Connect over TLS right away (SMTPS), usually on port 465.
+    Implicit,
+    /// Connect in plain text and require an upgrade via STARTTLS.
+    Starttls,
+    /// Upgrade via STARTTLS if the server supports it, otherwise stay in plain text.
+    Opportunistic,
+    /// Never encrypt the connection.
+    None,
+}
+
+impl ServerConfig {
+    pub fn auth_mechanisms(&self) -> Vec<AuthMechanism> {
+        match (&self.auth_mechanisms, self.auth) {
+            (Some(mechanisms), _) => mechanisms.clone(),
+            (None, AuthMethod::Password) => vec![AuthMechanism::Plain, AuthMechanism::Login],
+            (None, AuthMethod::OAuth2) => vec![AuthMechanism::Xoauth2],
+        }
+    }
+
+    pub fn tls_mode(&self) -> TlsMode {
+        match self.tls {
+            Some(mode) => mode,
+            None if self.port == 465 => TlsMode::Implicit,
+            None => TlsMode::Starttls,
+        }
+    }
+
+    /// Checks for settings that parse fine but can't work together.
+    pub fn validate(&self) -> Result<(), String> {
+        let mode = self.tls_mode();
+
+        match (mode, self.port) {
+            (TlsMode::Implicit, 25 | 587) => return Err(format!(
+                "Port {} expects STARTTLS, not implicit TLS. Set `tls = \"starttls\"` or use port 465.",
+                self.port
+            )),
+            (TlsMode::Starttls | TlsMode::Opportunistic | TlsMode::None, 465) => return Err(
+                "Port 465 expects implicit TLS. Set `tls = \"implicit\"` or use port 587.".to_string()
+            ),
+            (_, 0) => return Err("Invalid port 0.".to_string()),
+            _ => {}
+        }
+
+        if self.timeout_secs == Some(0) { return Err("`timeout_secs` must be at least 1.".to_string()) }
+        if !(self.retry_backoff.is_finite() && self.retry_backoff >= 0.0) {
+            return Err("`retry_backoff` must be a positive number of seconds.".to_string())
+        }
+
+        if let Some(pin) = &self.pin_sha256 {
+            let pin = tls::normalize_fingerprint(pin);
+            if pin.len() != 64 || !pin.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err("`pin_sha256` must be a SHA-256 fingerprint, like `AB:CD:...` or `abcd...`.".to_string())
+            }
+
+            if mode == TlsMode::None { return Err("`pin_sha256` requires TLS.".to_string()) }
+
+            // Accepting anything and then checking the pin would work, but it's
+            // almost certainly a mistake, and `ca_file` does the same job properly.
+            if self.danger_accept_invalid_certs {
+                return Err("`pin_sha256` can't be combined with `danger_accept_invalid_certs`. For a self-signed certificate, set `ca_file` to the certificate itself.".to_string())
+            }
+        }
+
+        if self.client_cert.is_some() || self.client_key.is_some() {
+            return Err("`client_cert` and `client_key` aren't supported yet: the version of lettre sendmail is built with can't present a client certificate.".to_string())
+        }
+
+        if self.password_source.is_some() && self.password_command.is_some() {
+            return Err("Use either `password_command` or `password_source`, not both.".to_string())
+        }
+
+        let mechanisms = self.auth_mechanisms();
+        if mechanisms.is_empty() { return Err("`auth_mechanisms` can't be empty.".to_string()) }
+
+        match self.auth {
+            AuthMethod::Password if mechanisms.contains(&AuthMechanism::Xoauth2) => return Err(
+                "`xoauth2` needs an access token. Set `auth = \"oauth2\"` or remove it from `auth_mechanisms`.".to_string()
+            ),
+            AuthMethod::OAuth2 if mechanisms.iter().any(|mechanism| *mechanism != AuthMechanism::Xoauth2) => return Err(
+                "`auth = \"oauth2\"` only works with the `xoauth2` mechanism.".to_string()
+            ),
+            _ => {}
+        }
+
+        if self.auth == AuthMethod::OAuth2 {
+            if self.username.is_none() { return Err("`auth = \"oauth2\"` requires `username`.".to_string()) }
+            if self.client_id.is_none() { return Err("`auth = \"oauth2\"` requires `client_id`.".to_string()) }
+            if self.token_endpoint.is_none() { return Err("`auth = \"oauth2\"` requires `token_endpoint`.".to_string()) }
+        }
+
+        Ok(())
+    }
+}
+
+/// Settings that apply to all accounts, read from `config.toml` in the config
+/// directory, or from the file given with `--config` or `SENDMAIL_CONFIG`.
+#[derive(Deserialize, Default)]
+pub struct Settings {
+    /// Account to use when none is given on the command line.
+    pub default_account: Option<String>,
+
+    /// Short names for accounts, such as `w = "work"`.
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+
+    /// Settings every account inherits, just like `defaults.toml`.
+    #[serde(default)]
+    pub defaults: Table,
+
+    /// Accounts defined in this file as `[accounts.<name>]`, next to the ones
+    /// in their own file.
+    #[serde(default)]
+    pub accounts: BTreeMap<String, Table>,
+
+    /// Where the settings were read from.
+    #[serde(skip)]
+    pub path: PathBuf,
+
+    /// Whether `path` was given explicitly. In that case, it's the only file
+    /// that's read, so the config directory is ignored.
+    #[serde(skip)]
+    pub explicit: bool,
+}
+
+/// Where an account is defined.
+pub enum Location {
+    /// In its own file.
+    File(PathBuf),
+    /// In the `[accounts]` table of the settings file.
+    Inline(PathBuf, String),
+    /// Nowhere, but there are environment variables for it.
+    Environment(String),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::File(path) => write!(f, "{}", path.display()),
+            Location::Inline(path, name) => write!(f, "{}: [accounts.{}]", path.display(), name),
+            Location::Environment(name) => write!(f, "$SENDMAIL_{}_*", env_name(name)),
+        }
+    }
+}
+
+/// Name of the settings file, which is not an account.
+pub const SETTINGS_FILE: &str = "config.toml";
+
+/// Name of the file every account inherits from, which is not an account either.
+pub const DEFAULTS_FILE: &str = "defaults.toml";
+
+/// Key pointing to another account to inherit settings from.
+pub const EXTENDS_KEY: &str = "extends";
+
+/// Sections of `Config` that are a `ServerConfig`.
+pub const SERVER_SECTIONS: [&str; 2] = ["smtp", "imap"];
+
+/// Directory holding one TOML file per account.
+pub fn config_dir() -> PathBuf {
+    let directories = AppDirs::new(Some(crate_name!()), false).unwrap();
+    directories.config_dir
+}
+
+/// Reads the settings from `path`, or from `config.toml` in the config directory.
+pub fn get_settings(path: Option<PathBuf>) -> Settings {
+    let explicit = path.is_some();
+    let path = path.unwrap_or_else(|| config_dir().join(SETTINGS_FILE));
+
+    let mut settings: Settings = if explicit || path.exists() {
+        let toml = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("{}: Couldn't read settings: {e}", path.display()));
+
+        toml::from_str(&toml).unwrap_or_else(|e| panic!("{}: {e}", path.display()))
+    } else {
+        Settings::default()
+    };
+
+    settings.path = path;
+    settings.explicit = explicit;
+    settings
+}
+
+impl Settings {
+    /// Directory with one file per account, unless an explicit config file is used.
+    pub fn accounts_dir(&self) -> Option<PathBuf> {
+        if self.explicit { return None }
+        Some(config_dir())
+    }
+
+    /// Names of all configured accounts, sorted.
+    pub fn account_names(&self) -> Vec<String> {
+        let mut accounts: Vec<String> = self.accounts.keys().cloned().collect();
+
+        if let Some(directory) = self.accounts_dir().filter(|directory| directory.exists()) {
+            let entries = fs::read_dir(&directory)
+                .unwrap_or_else(|e| panic!("{}: Couldn't read config directory: {e}", directory.display()));
+
+            accounts.extend(entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().is_file())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .filter(|name| name != SETTINGS_FILE && name != DEFAULTS_FILE));
+        }
+
+        accounts.sort();
+        accounts.dedup();
+        accounts
+    }
+
+    /// The accounts that `extends` `account` directly. An account that's
+    /// extended may be incomplete on its own, like `defaults.toml`.
+    pub fn extended_by(&self, account: &str) -> Vec<String> {
+        self.account_names().into_iter()
+            .filter(|name| {
+                let table = self.locate(name).and_then(|location| self.read_account(&location));
+                table.is_ok_and(|table| table.get(EXTENDS_KEY).and_then(Value::as_str) == Some(account))
+            })
+            .collect()
+    }
+
+    /// Turns the account given on the command line, which may be an alias or
+    /// missing altogether, into the name of an account.
+    pub fn resolve_account(&self, account: Option<String>) -> String {
+        let account = account.or_else(|| self.default_account.clone()).unwrap_or_else(|| panic!(
+            "No account given and no `default_account` set in {}.",
+            self.path.display()
+        ));
+
+        match self.aliases.get(&account) {
+            Some(target) => target.clone(),
+            None => account,
+        }
+    }
+
+    /// Where `account` is defined. Fails if it's defined in more than one place, or nowhere.
+    /// Accounts that aren't defined anywhere can still be made up entirely of
+    /// environment variables.
+    pub fn locate(&self, account: &str) -> Result<Location, String> {
+        let file = self.accounts_dir()
+            .map(|directory| directory.join(account))
+            .filter(|file| file.is_file() && account != SETTINGS_FILE && account != DEFAULTS_FILE);
+
+        match (self.accounts.contains_key(account), file) {
+            (true, Some(file)) => Err(format!(
+                "Account `{}` is defined both in {} and {}.",
+                account, self.path.display(), file.display()
+            )),
+            (true, None) => Ok(Location::Inline(self.path.clone(), account.to_string())),
+            (false, Some(file)) => Ok(Location::File(file)),
+            // Only the account's own variables, `SENDMAIL_SMTP_PORT` doesn't make every name an account.
+            (false, None) if !env_values(&account_prefix(account)).is_empty() => Ok(Location::Environment(account.to_string())),
+            (false, None) => Err(format!("Unknown account `{}`.", account)),
+        }
+    }
+
+    pub fn get_config(&self, account: &str) -> Config {
+        self.try_get_config(account).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_get_config(&self, account: &str) -> Result<Config, String> {
+        let location = self.locate(account)?;
+        let table = self.get_merged_table(account)?;
+
+        let config = from_table(table)
+            .map_err(|e| format!("{}: {}", location, e))?;
+
+        config.validate()
+            .map_err(|e| format!("{}: {}", location, e))?;
+
+        Ok(config)
+    }
+
+    /// The effective config of `account` as a TOML table: the defaults,
+    /// overridden by the chain of `extends`, overridden by the account itself,
+    /// overridden by environment variables.
+    pub fn get_merged_table(&self, account: &str) -> Result<Table, String> {
+        let mut layers = Vec::new();
+        let mut name = account.to_string();
+
+        loop {
+            if layers.iter().any(|(seen, _)| *seen == name) {
+                return Err(format!("{}: `extends` loops back to `{}`.", self.locate(account)?, name));
+            }
+
+            let location = self.locate(&name)?;
+            let mut table = self.read_account(&location)?;
+
+            let parent = match table.remove(EXTENDS_KEY) {
+                Some(Value::String(parent)) => Some(parent),
+                Some(_) => return Err(format!("{}: `extends` must be a string.", location)),
+                None => None,
+            };
+
+            layers.push((name, table));
+
+            match parent {
+                Some(parent) => name = parent,
+                None => break,
+            }
+        }
+
+        let mut merged = Table::new();
+
+        if let Some(directory) = self.accounts_dir() {
+            let defaults = directory.join(DEFAULTS_FILE);
+            if defaults.exists() { merge(&mut merged, read_table(&defaults)?) }
+        }
+
+        merge(&mut merged, self.defaults.clone());
+
+        for (_, table) in layers.into_iter().rev() {
+            merge(&mut merged, table);
+        }
+
+        merge(&mut merged, env_overrides(account));
+        Ok(merged)
+    }
+
+    fn read_account(&self, location: &Location) -> Result<Table, String> {
+        match location {
+            Location::File(file) => read_table(file),
+            Location::Inline(_, name) => Ok(self.accounts[name].clone()),
+            Location::Environment(_) => Ok(Table::new()),
+        }
+    }
+}
+
+pub fn from_table(table: Table) -> Result<Config, String> {
+    // The error mentions the section on a separate line, e.g. "missing field `port`\nin `smtp`".
+    Config::deserialize(table).map_err(|e| e.to_string().trim().replace('\n', " "))
+}
+
+fn read_table(file: &Path) -> Result<Table, String> {
+    let toml = fs::read_to_string(file)
+        .map_err(|e| format!("{}: Couldn't read config file: {e}", file.display()))?;
+
+    toml.parse().map_err(|e| format!("{}: {e}", file.display()))
+}
+
+/// `account` as it appears in environment variables, e.g. `SCHOOL` or `MY_WORK`.
+pub fn env_name(account: &str) -> String {
+    account
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect()
+}
+
+/// Config values set through the environment, such as `SENDMAIL_SMTP_PORT` for
+/// every account, or `SENDMAIL_WORK_SMTP_PORT` for just the account `work`.
+pub fn env_overrides(account: &str) -> Table {
+    let mut overrides = env_values("SENDMAIL_");
+    merge(&mut overrides, env_values(&account_prefix(account)));
+    overrides
+}
+
+fn account_prefix(account: &str) -> String {
+    format!("SENDMAIL_{}_", env_name(account))
+}
+
+/// The config values in variables starting with `prefix`.
+fn env_values(prefix: &str) -> Table {
+    let mut values = Table::new();
+
+    for (path, kind) in field_paths() {
+        let variable = format!("{}{}", prefix, path.join("_").to_uppercase());
+        let Ok(value) = env::var(&variable) else { continue };
+
+        let (key, sections) = path.split_last().unwrap();
+        let mut table = &mut values;
+
+        for section in sections {
+            table = table.entry(*section)
+                .or_insert_with(|| Value::Table(Table::new()))
+                .as_table_mut()
+                .unwrap();
+        }
+
+        table.insert(key.to_string(), parse_env_value(value, kind));
+    }
+
+    values
+}
+
+/// Every key an account can have, with the section it's in and the kind of
+/// value it takes.
+fn field_paths() -> Vec<(Vec<&'static str>, ValueKind)> {
+    let mut paths = Vec::new();
+
+    for field in field_names::<Config>() {
+        if SERVER_SECTIONS.contains(field) {
+            paths.extend(field_names::<ServerConfig>().iter().map(|key| (vec![*field, key], value_kind::<ServerConfig>(key))));
+        } else {
+            paths.push((vec![*field], value_kind::<Config>(field)));
+        }
+    }
+
+    paths
+}
+
+/// Reads a value as the kind of value its key takes. Text stays text, even
+/// when it looks like a number, such as a username `4410`. Everything else is
+/// read as TOML when possible.
+fn parse_env_value(value: String, kind: ValueKind) -> Value {
+    if kind == ValueKind::String { return Value::String(value) }
+
+    match format!("value = {}", value).parse::<Table>() {
+        Ok(mut table) => table.remove("value").unwrap(),
+        Err(_) => Value::String(value),
+    }
+}
+
+/// Merges `overrides` into `base` key by key, recursing into tables.
+fn merge(base: &mut Table, overrides: Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overrides)) => merge(base, overrides),
+            (_, value) => { base.insert(key, value); }
+        }
+    }
+}
+
+/// Returns the field names of a struct that derives `Deserialize`, so the
+/// list of known keys can't drift from the actual config types.
+pub fn field_names<T: DeserializeOwned>() -> &'static [&'static str] {
+    match T::deserialize(FieldNames) {
+        Err(FieldNamesError(fields)) => fields,
+        Ok(_) => &[],
+    }
+}
+
+/// Deserializer that records the fields a struct asks for and then bails out.
+struct FieldNames;
+
+#[derive(Debug)]
+struct FieldNamesError(&'static [&'static str]);
+
+impl fmt::Display for FieldNamesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "fields: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for FieldNamesError {}
+
+impl de::Error for FieldNamesError {
+    fn custom<T: fmt::Display>(_: T) -> Self {
+        FieldNamesError(&[])
+    }
+}
+
+/// What a config key takes, as far as reading it from the environment goes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ValueKind {
+    String,
+    Number,
+    Bool,
+    Other,
+}
+
+/// Returns the kind of value `field` of a struct that derives `Deserialize`
+/// takes, by having it deserialize just that field from a probe.
+fn value_kind<T: DeserializeOwned>(field: &'static str) -> ValueKind {
+    match T::deserialize(KindProbe(field)) {
+        Err(KindProbeError(kind)) => kind,
+        Ok(_) => ValueKind::Other,
+    }
+}
+
+/// Deserializer that hands a struct a single field, and records what the
+/// field asks for.
+struct KindProbe(&'static str);
+
+/// Stands in for the value of the probed field.
+struct KindRecorder;
+
+#[derive(Debug)]
+struct KindProbeError(ValueKind);
+
+impl fmt::Display for KindProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "kind: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for KindProbeError {}
+
+impl de::Error for KindProbeError {
+    fn custom<T: fmt::Display>(_: T) -> Self {
+        KindProbeError(ValueKind::Other)
+    }
+}
+
+impl<'de> Deserializer<'de> for KindProbe {
+    type Error = KindProbeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(KindProbeError(ValueKind::Other))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _: &'static str, _: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+impl<'de> de::MapAccess<'de> for KindProbe {
+    type Error = KindProbeError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+        seed.deserialize(de::value::BorrowedStrDeserializer::new(self.0)).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
+        seed.deserialize(KindRecorder)
+    }
+}
+
+impl<'de> Deserializer<'de> for KindRecorder {
+    type Error = KindProbeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(KindProbeError(ValueKind::Other))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(KindProbeError(ValueKind::Bool))
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(KindProbeError(ValueKind::Number))
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(KindProbeError(ValueKind::String))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        i128 u128 char bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+
+    // Numbers of every size are numbers, and borrowed text is still text.
+    fn deserialize_i8<V: Visitor<'de>>(self, v: V) -> Result<V::Value, Self::Error> { self.deserialize_u64(v) }
+    fn deserialize_i16<V: Visitor<'de>>(self, v: V) -> Result<V::Value, Self::Error> { self.deserialize_u64(v) }
+    fn deserialize_i32<V: Visitor<'de>>(self, v: V) -> Result<V::Value, Self::Error> { self.deserialize_u64(v) }
+    fn deserialize_i64<V: Visitor<'de>>(self, v: V) -> Result<V::Value, Self::Error> { self.deserialize_u64(v) }
+    fn deserialize_u8<V: Visitor<'de>>(self, v: V) -> Result<V::Value, Self::Error> { self.deserialize_u64(v) }
+    fn deserialize_u16<V: Visitor<'de>>(self, v: V) -> Result<V::Value, Self::Error> { self.deserialize_u64(v) }
+    fn deserialize_u32<V: Visitor<'de>>(self, v: V) -> Result<V::Value, Self::Error> { self.deserialize_u64(v) }
+    fn deserialize_f32<V: Visitor<'de>>(self, v: V) -> Result<V::Value, Self::Error> { self.deserialize_u64(v) }
+    fn deserialize_f64<V: Visitor<'de>>(self, v: V) -> Result<V::Value, Self::Error> { self.deserialize_u64(v) }
+    fn deserialize_str<V: Visitor<'de>>(self, v: V) -> Result<V::Value, Self::Error> { self.deserialize_string(v) }
+}
+
+impl<'de> Deserializer<'de> for FieldNames {
+    type Error = FieldNamesError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(FieldNamesError(&[]))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _: &'static str, fields: &'static [&'static str], _: V) -> Result<V::Value, Self::Error> {
+        Err(FieldNamesError(fields))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Settings read from an explicit file, so the real config directory is left alone.
+    fn settings(toml: &str) -> Settings {
+        let mut settings: Settings = toml::from_str(toml).unwrap();
+        settings.path = PathBuf::from("config.toml");
+        settings.explicit = true;
+        settings
+    }
+
+    #[test]
+    fn probes_the_kind_of_each_key() {
+        assert_eq!(value_kind::<Config>("name"), ValueKind::String);
+        assert_eq!(value_kind::<Config>("undo_seconds"), ValueKind::Number);
+        assert_eq!(value_kind::<Config>("transport"), ValueKind::Other);
+        assert_eq!(value_kind::<Config>("sendmail_args"), ValueKind::Other);
+        assert_eq!(value_kind::<ServerConfig>("username"), ValueKind::String);
+        assert_eq!(value_kind::<ServerConfig>("port"), ValueKind::Number);
+        assert_eq!(value_kind::<ServerConfig>("retry_backoff"), ValueKind::Number);
+        assert_eq!(value_kind::<ServerConfig>("danger_accept_invalid_certs"), ValueKind::Bool);
+        assert_eq!(value_kind::<ServerConfig>("auth_mechanisms"), ValueKind::Other);
+    }
+
+    #[test]
+    fn lists_every_key_with_its_section() {
+        let paths = field_paths();
+
+        assert!(paths.contains(&(vec!["name"], ValueKind::String)));
+        assert!(paths.contains(&(vec!["smtp", "port"], ValueKind::Number)));
+        assert!(paths.contains(&(vec!["imap", "hostname"], ValueKind::String)));
+        // Sections only show up through their keys.
+        assert!(!paths.iter().any(|(path, _)| *path == ["smtp"]));
+    }
+
+    #[test]
+    fn parses_env_values_as_their_kind() {
+        assert_eq!(parse_env_value("4410".to_string(), ValueKind::String), Value::String("4410".to_string()));
+        assert_eq!(parse_env_value("true".to_string(), ValueKind::String), Value::String("true".to_string()));
+        assert_eq!(parse_env_value("587".to_string(), ValueKind::Number), Value::Integer(587));
+        assert_eq!(parse_env_value("1.5".to_string(), ValueKind::Number), Value::Float(1.5));
+        assert_eq!(parse_env_value("true".to_string(), ValueKind::Bool), Value::Boolean(true));
+
+        let mechanisms = Value::Array(vec![Value::String("plain".to_string())]);
+        assert_eq!(parse_env_value("[\"plain\"]".to_string(), ValueKind::Other), mechanisms);
+
+        // Left for deserializing to complain about.
+        assert_eq!(parse_env_value("lots".to_string(), ValueKind::Number), Value::String("lots".to_string()));
+    }
+
+    #[test]
+    fn resolves_aliases_and_the_default_account() {
+        let settings = settings("
+            default_account = 'school'
+            aliases = { w = 'work' }
+            accounts.work.name = 'Work'
+            accounts.school.name = 'School'
+        ");
+
+        assert_eq!(settings.resolve_account(None), "school");
+        assert_eq!(settings.resolve_account(Some("w".to_string())), "work");
+        assert_eq!(settings.resolve_account(Some("work".to_string())), "work");
+        assert_eq!(settings.account_names(), ["school", "work"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "No account given and no `default_account` set in config.toml.")]
+    fn requires_an_account_without_a_default() {
+        settings("").resolve_account(None);
+    }
+
+    #[test]
+    fn finds_accounts_in_files_and_the_settings() {
+        // Only this test uses the config directory.
+        let home = env::temp_dir().join(format!("sendmail-test-{}-config", std::process::id()));
+        let directory = home.join("sendmail");
+        fs::create_dir_all(&directory).unwrap();
+        env::set_var("XDG_CONFIG_HOME", &home);
+
+        for file in [SETTINGS_FILE, DEFAULTS_FILE, "work", "school"] {
+            fs::write(directory.join(file), "name = 'From a file'").unwrap();
+        }
+
+        let mut settings = settings("accounts.school.name = 'Inline'\naccounts.home.name = 'Inline'");
+        settings.explicit = false;
+
+        assert_eq!(settings.account_names(), ["home", "school", "work"]);
+        assert!(matches!(settings.locate("work"), Ok(Location::File(file)) if file == directory.join("work")));
+        assert!(matches!(settings.locate("home"), Ok(Location::Inline(_, name)) if name == "home"));
+        assert!(settings.locate("school").err().unwrap().starts_with("Account `school` is defined both in config.toml and "));
+        assert!(settings.locate(DEFAULTS_FILE).is_err());
+
+        // An explicit file is all there is.
+        let explicit = directory.join("explicit.toml");
+        fs::write(&explicit, "default_account = 'home'\naccounts.home.name = 'Explicit'").unwrap();
+        let settings = get_settings(Some(explicit));
+
+        assert_eq!(settings.account_names(), ["home"]);
+        assert!(settings.locate("work").is_err());
+        assert_eq!(settings.get_merged_table("home").unwrap()["name"].as_str(), Some("Explicit"));
+
+        fs::remove_dir_all(home).unwrap();
+    }
+
+    #[test]
+    fn merges_key_by_key() {
+        let mut base: Table = toml::from_str("name = 'Base'\nsmtp = { hostname = 'a', port = 587 }").unwrap();
+        merge(&mut base, toml::from_str("smtp = { port = 465 }\nemail = 'me@example.com'").unwrap());
+
+        assert_eq!(base, toml::from_str("name = 'Base'\nemail = 'me@example.com'\nsmtp = { hostname = 'a', port = 465 }").unwrap());
+    }
+
+    #[test]
+    fn inherits_from_defaults_and_extends() {
+        let settings = settings("
+            [defaults]
+            name = 'Robin'
+            smtp = { hostname = 'smtp.example.com', port = 587 }
+
+            [accounts.base]
+            smtp = { username = 'shared', port = 2525 }
+
+            [accounts.school]
+            extends = 'base'
+            email = '4410@example.com'
+            smtp = { port = 465, tls = 'implicit' }
+        ");
+
+        let merged = settings.get_merged_table("school").unwrap();
+        let smtp = merged["smtp"].as_table().unwrap();
+
+        assert_eq!(merged["name"].as_str(), Some("Robin"));
+        assert_eq!(smtp["hostname"].as_str(), Some("smtp.example.com"));
+        assert_eq!(smtp["username"].as_str(), Some("shared"));
+        assert_eq!(smtp["port"].as_integer(), Some(465));
+        assert!(!merged.contains_key(EXTENDS_KEY));
+
+        assert_eq!(settings.extended_by("base"), ["school"]);
+        assert!(settings.extended_by("school").is_empty());
+    }
+
+    #[test]
+    fn rejects_broken_extends() {
+        let settings = settings("
+            accounts.a.extends = 'b'
+            accounts.b.extends = 'c'
+            accounts.c.extends = 'a'
+            accounts.lost.extends = 'nowhere'
+            accounts.odd.extends = 1
+        ");
+
+        assert_eq!(settings.get_merged_table("a").err(), Some("config.toml: [accounts.a]: `extends` loops back to `a`.".to_string()));
+        assert_eq!(settings.get_merged_table("lost").err(), Some("Unknown account `nowhere`.".to_string()));
+        assert_eq!(settings.get_merged_table("odd").err(), Some("config.toml: [accounts.odd]: `extends` must be a string.".to_string()));
+    }
+
+    #[test]
+    fn reads_accounts_from_the_environment() {
+        // Only this test sets variables, each under its own account.
+        env::set_var("SENDMAIL_SIGNATURE", "everyone");
+        env::set_var("SENDMAIL_ENV_ONLY_SIGNATURE", "just me");
+        env::set_var("SENDMAIL_ENV_ONLY_NAME", "Env");
+        env::set_var("SENDMAIL_ENV_ONLY_EMAIL", "env@example.com");
+        env::set_var("SENDMAIL_ENV_ONLY_SMTP_HOSTNAME", "smtp.example.com");
+        env::set_var("SENDMAIL_ENV_ONLY_SMTP_PORT", "2525");
+        env::set_var("SENDMAIL_ENV_ONLY_SMTP_USERNAME", "4410");
+        env::set_var("SENDMAIL_ENV_INLINE_SMTP_PORT", "465");
+
+        let settings = settings("
+            [accounts.env-inline]
+            name = 'Inline'
+            email = 'inline@example.com'
+            smtp = { hostname = 'smtp.example.com', port = 587, tls = 'implicit' }
+        ");
+
+        assert!(matches!(settings.locate("env-only"), Ok(Location::Environment(_))));
+        let config = settings.get_config("env-only");
+        let smtp = config.smtp.unwrap();
+        assert_eq!((smtp.port, smtp.username.as_deref()), (2525, Some("4410")));
+        // The account's own variables win over the ones for every account.
+        assert_eq!(config.signature.as_deref(), Some("just me"));
+
+        let config = settings.get_config("env-inline");
+        assert_eq!(config.smtp.unwrap().port, 465);
+        assert_eq!(config.signature.as_deref(), Some("everyone"));
+
+        // Variables for every account don't make up an account of their own.
+        assert_eq!(settings.locate("env-typo").err(), Some("Unknown account `env-typo`.".to_string()));
+    }
+}
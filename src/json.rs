@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+
+/// Parses a JSON object and returns its top-level fields as text: strings as
+/// they are, numbers and booleans as written, and `null` as an empty string.
+/// Nested objects and arrays are skipped. Token endpoints don't return
+/// anything more complicated that we care about.
+pub fn parse_object(json: &str) -> Option<HashMap<String, String>> {
+    JsonParser { chars: json.trim().chars().peekable() }.object()
+}
+
+/// Parses a JSON array of objects, like the rows of a mail merge, with the
+/// same limitations as `parse_object`.
+pub fn parse_objects(json: &str) -> Option<Vec<HashMap<String, String>>> {
+    let mut parser = JsonParser { chars: json.trim().chars().peekable() };
+    let mut objects = Vec::new();
+
+    parser.expect('[')?;
+    if parser.peek()? == ']' { return Some(objects) }
+
+    loop {
+        objects.push(parser.object()?);
+
+        match parser.next()? {
+            ',' => continue,
+            ']' => return Some(objects),
+            _ => return None,
+        }
+    }
+}
+
+struct JsonParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl JsonParser<'_> {
+    fn object(&mut self) -> Option<HashMap<String, String>> {
+        let mut fields = HashMap::new();
+
+        self.expect('{')?;
+        if self.peek()? == '}' {
+            self.next();
+            return Some(fields);
+        }
+
+        loop {
+            let key = self.string()?;
+            self.expect(':')?;
+
+            if let Some(value) = self.value()? {
+                fields.insert(key, value);
+            }
+
+            match self.next()? {
+                ',' => continue,
+                '}' => return Some(fields),
+                _ => return None,
+            }
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.peek().copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.next()
+    }
+
+    fn expect(&mut self, expected: char) -> Option<()> {
+        (self.next()? == expected).then_some(())
+    }
+
+    /// Parses any value, returning it as a string unless it's nested.
+    fn value(&mut self) -> Option<Option<String>> {
+        match self.peek()? {
+            '"' => Some(Some(self.string()?)),
+            '{' | '[' => self.nested().map(|_| None),
+            _ => {
+                let mut literal = String::new();
+                while let Some(c) = self.chars.next_if(|c| !matches!(c, ',' | '}' | ']') && !c.is_whitespace()) {
+                    literal.push(c);
+                }
+
+                match literal.as_str() {
+                    "true" | "false" => Some(Some(literal)),
+                    "null" => Some(Some(String::new())),
+                    number if number.parse::<f64>().is_ok() => Some(Some(literal)),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    fn nested(&mut self) -> Option<()> {
+        let mut depth = 0;
+
+        loop {
+            match self.peek()? {
+                '"' => { self.string()?; }
+                '{' | '[' => { self.chars.next(); depth += 1; }
+                '}' | ']' => {
+                    self.chars.next();
+                    depth -= 1;
+                    if depth == 0 { return Some(()) }
+                }
+                _ => { self.chars.next(); }
+            }
+        }
+    }
+
+    /// Reads the hex digits after `\u`. Characters outside the Basic
+    /// Multilingual Plane, like emoji, are written as two of those: a surrogate pair.
+    fn escaped_char(&mut self) -> Option<char> {
+        let code = hex_code(&mut self.chars)?;
+        if !(0xd800..0xdc00).contains(&code) {
+            return Some(char::from_u32(code).unwrap_or('\u{fffd}'));
+        }
+
+        // Only take the next escape if it's the other half of the pair.
+        let mut rest = self.chars.clone();
+        let low = (rest.next() == Some('\\') && rest.next() == Some('u'))
+            .then(|| hex_code(&mut rest))
+            .flatten()
+            .filter(|low| (0xdc00..0xe000).contains(low));
+
+        let Some(low) = low else { return Some('\u{fffd}') };
+        self.chars = rest;
+
+        char::from_u32(0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.expect('"')?;
+        let mut string = String::new();
+
+        loop {
+            match self.chars.next()? {
+                '"' => return Some(string),
+                '\\' => match self.chars.next()? {
+                    'n' => string.push('\n'),
+                    't' => string.push('\t'),
+                    'r' => string.push('\r'),
+                    'b' => string.push('\u{8}'),
+                    'f' => string.push('\u{c}'),
+                    'u' => string.push(self.escaped_char()?),
+                    c => string.push(c),
+                },
+                c => string.push(c),
+            }
+        }
+    }
+}
+
+fn hex_code(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<u32> {
+    let code: String = (0..4).filter_map(|_| chars.next()).collect();
+    u32::from_str_radix(&code, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_values_as_text() {
+        let fields = parse_object(r#"{"a": "text", "b": 3599, "c": -1.5e3, "d": true, "e": false, "f": null}"#).unwrap();
+
+        assert_eq!(fields["a"], "text");
+        assert_eq!(fields["b"], "3599");
+        assert_eq!(fields["c"], "-1.5e3");
+        assert_eq!(fields["d"], "true");
+        assert_eq!(fields["e"], "false");
+        assert_eq!(fields["f"], "");
+    }
+
+    #[test]
+    fn skips_nested_values() {
+        let fields = parse_object(r#"{"scope": ["a", {"b": "}"}], "token": "x", "nested": {"c": [1, 2]}}"#).unwrap();
+
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields["token"], "x");
+    }
+
+    #[test]
+    fn unescapes_strings() {
+        let fields = parse_object(r#"{"a": "quote \" backslash \\ slash \/ tab \t line \n", "b": "café"}"#).unwrap();
+
+        assert_eq!(fields["a"], "quote \" backslash \\ slash / tab \t line \n");
+        assert_eq!(fields["b"], "café");
+    }
+
+    #[test]
+    fn joins_surrogate_pairs() {
+        let fields = parse_object(r#"{"a": "\ud83d\ude00", "b": "\ud83dx", "c": "\ud83d\u0041", "d": "\u00e9"}"#).unwrap();
+
+        assert_eq!(fields["a"], "😀");
+        assert_eq!(fields["b"], "\u{fffd}x");
+        assert_eq!(fields["c"], "\u{fffd}A");
+        assert_eq!(fields["d"], "é");
+    }
+
+    #[test]
+    fn rejects_invalid_json() {
+        assert!(parse_object("").is_none());
+        assert!(parse_object(r#"{"a": "unterminated}"#).is_none());
+        assert!(parse_object(r#"{"a": nope}"#).is_none());
+        assert!(parse_object(r#"{"a": 1"#).is_none());
+        assert!(parse_object(r#"["a"]"#).is_none());
+    }
+
+    #[test]
+    fn parses_arrays_of_objects() {
+        let rows = parse_objects(r#" [ {"name": "Alice"}, {"name": "Bob", "age": 7} ] "#).unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0]["name"], "Alice");
+        assert_eq!(rows[1]["age"], "7");
+
+        assert_eq!(parse_objects("[]").unwrap().len(), 0);
+        assert!(parse_objects(r#"{"name": "Alice"}"#).is_none());
+        assert!(parse_objects(r#"[{"name": "Alice"} {"name": "Bob"}]"#).is_none());
+    }
+}
@@ -0,0 +1,61 @@
+use std::io::{self, ErrorKind, Write};
+use std::process::{Command, Stdio};
+
+use clap::crate_name;
+
+use crate::password::Secret;
+
+/// Talks to the Secret Service (GNOME Keyring, KWallet, KeePassXC, ...) through
+/// `secret-tool` from libsecret, so we don't have to speak D-Bus ourselves.
+const SECRET_TOOL: &str = "secret-tool";
+
+/// Looks up the password stored for `account`, if there is one.
+pub fn lookup(account: &str) -> Option<Secret> {
+    let output = Command::new(SECRET_TOOL)
+        .arg("lookup")
+        .args(attributes(account))
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap_or_else(|e| not_available(e));
+
+    let password = Secret::new(String::from_utf8(output.stdout)
+        .unwrap_or_else(|_| panic!("{}: Keyring returned a password that isn't valid UTF-8.", account)));
+
+    // secret-tool exits with 1 and prints nothing when there's no such item.
+    if !output.status.success() || password.expose().is_empty() { return None }
+
+    Some(password)
+}
+
+/// Stores `password` for `account`, replacing the existing one.
+pub fn store(account: &str, password: &Secret) {
+    let mut child = Command::new(SECRET_TOOL)
+        .arg("store")
+        .arg(format!("--label={} password for {}", crate_name!(), account))
+        .args(attributes(account))
+        .stdin(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .unwrap_or_else(|e| not_available(e));
+
+    // Without a trailing newline, secret-tool stores exactly what it receives.
+    child.stdin.take().unwrap().write_all(password.expose().as_bytes())
+        .unwrap_or_else(|e| panic!("{}: Couldn't pass password to {}: {e}", account, SECRET_TOOL));
+
+    let status = child.wait().unwrap_or_else(|e| panic!("{}: {} failed: {e}", account, SECRET_TOOL));
+    if !status.success() {
+        panic!("{}: Couldn't store password in keyring ({}).", account, status)
+    }
+}
+
+fn attributes(account: &str) -> [&str; 4] {
+    ["service", crate_name!(), "account", account]
+}
+
+fn not_available(e: io::Error) -> ! {
+    match e.kind() {
+        ErrorKind::NotFound => panic!("`password_source = \"keyring\"` needs {} (usually in the libsecret-tools or libsecret package).", SECRET_TOOL),
+        _ => panic!("Couldn't run {}: {e}", SECRET_TOOL),
+    }
+}
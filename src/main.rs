@@ -1,53 +1,167 @@
+use std::borrow::Borrow;
+use std::error::Error;
 use std::fs;
-use std::path::Path;
-use clap::{Parser, crate_name};
-use serde::Deserialize;
+use std::io;
+use std::process;
+use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use clap::{Parser, Subcommand};
 
 use lettre::Message;
+use lettre::address::Envelope;
 use lettre::message::Attachment;
 use lettre::message::header::{ContentType, To, Cc, Bcc};
 use lettre::message::{Mailbox, Mailboxes};
 use lettre::message::{SinglePart, MultiPart};
 
-use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::transport::smtp::SmtpTransportBuilder;
 use lettre::{SmtpTransport, Transport};
 
-use platform_dirs::AppDirs;
-use mime;
+mod agent;
+mod authinfo;
+mod check;
+mod config;
+mod json;
+mod keyring;
+mod merge;
+mod mta;
+mod oauth;
+mod output;
+mod password;
+mod queue;
+mod quota;
+mod time;
+mod tls;
 
-#[derive(Deserialize)]
-struct Config {
-    name: String,
-    email: String,
-    smtp: ServerConfig,
+use config::{AuthMechanism, AuthMethod, Config, Identity, PasswordSource, ServerConfig, Settings, TransportKind};
+use password::Secret;
 
-    #[allow(unused)]
-    imap: ServerConfig
+#[derive(Parser, Debug)]
+#[command(version, about, subcommand_negates_reqs = true, allow_missing_positional = true)]
+#[command(override_usage = "sendmail [OPTIONS] --subject <SUBJECT> --to <TO> [ACCOUNT] <PATH>...\n       sendmail [--config <PATH>] <COMMAND>")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Read settings and accounts from this file only, instead of `~/.config/sendmail/`.
+    #[arg(long, global = true, env = "SENDMAIL_CONFIG", value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    send: Option<SendArgs>,
 }
 
-#[derive(Deserialize)]
-struct ServerConfig {
-    hostname: String,
-    username: String,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check account config files for unknown keys, missing fields and malformed addresses.
+    CheckConfig {
+        /// Only check this account, instead of every file in the config directory.
+        account: Option<String>,
+    },
+
+    /// List the configured accounts with their from-address and SMTP server.
+    Accounts,
+
+    /// Print the effective config of an account, after applying `defaults.toml` and `extends`.
+    ShowConfig {
+        /// The account (or alias) to show. Defaults to `default_account`.
+        account: Option<String>,
+    },
+
+    /// Show how many emails an account sent in the last minute and day, against its `max_per_minute` and `max_per_day`.
+    Quota {
+        /// The account (or alias) to show. Defaults to `default_account`.
+        account: Option<String>,
+    },
 
-    #[allow(unused)]
-    port: u16,
+    /// Keep passwords in memory for a while, so they're only asked for once.
+    Agent {
+        /// How long to keep each password, in seconds.
+        #[arg(long, default_value_t = 3600, value_name = "SECONDS")]
+        ttl: u64,
+    },
+
+    /// Manage the emails waiting to be sent, because sending failed or they were sent with `--queue`.
+    Queue {
+        #[command(subcommand)]
+        command: QueueCommand,
+    },
+
+    /// Cancel emails that are waiting to be sent, because of `--delay` or `undo_seconds`, or because they're scheduled.
+    Cancel {
+        /// The IDs shown when the emails were sent.
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+
+    /// Manage passwords stored in the system keyring.
+    Password {
+        #[command(subcommand)]
+        command: PasswordCommand,
+    },
 }
 
-#[derive(Parser, Debug)]
-#[command(version, about)]
-struct Args {
-    /// The account to use, defined in `~/config/mail/`.
+#[derive(Subcommand, Debug)]
+enum QueueCommand {
+    /// List the queued emails.
+    List,
+
+    /// Try to send the queued emails now, with the account they were written for.
+    Flush {
+        /// Only send these emails, including scheduled ones that aren't due yet.
+        /// Defaults to every email that's due.
+        ids: Vec<String>,
+    },
+
+    /// Send the emails that are due, and nothing else. Meant to run from cron or a systemd timer.
+    Run,
+
+    /// Remove emails from the queue without sending them.
+    Rm {
+        /// The IDs of the emails, as shown by `sendmail queue list`.
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PasswordCommand {
+    /// Store the password (or OAuth2 refresh token) of an account in the keyring.
+    Set {
+        /// The account (or alias) to store the password for.
+        account: String,
+
+        /// Read the password from the first line of stdin, instead of asking for it.
+        #[arg(long)]
+        password_stdin: bool,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct SendArgs {
+    /// The account (or alias) to use, defined in `~/.config/sendmail/`. Defaults to `default_account`.
     #[arg()]
-    account: String,
+    account: Option<String>,
+
+    /// Same as the account positional. With this, every positional is a path.
+    #[arg(short = 'A', long = "account", value_name = "ACCOUNT")]
+    account_flag: Option<String>,
 
     /// Path to the body contents of the email, markdown is assumed and sent as HTML.
-    #[arg()]
-    path: String,
+    /// Every file is sent as its own email, with the same subject and recipients.
+    #[arg(required = true, value_name = "PATH")]
+    paths: Vec<String>,
 
-    /// Password for the SMTP account.
+    /// Password for the SMTP account, overrides all other password sources.
     #[arg(short, long)]
-    password: String,
+    password: Option<String>,
+
+    /// Read the password from the first line of stdin.
+    #[arg(long, conflicts_with = "password")]
+    password_stdin: bool,
 
     /// `Subject` header.
     #[arg(short, long)]
@@ -65,45 +179,578 @@ struct Args {
     #[arg(long)]
     bcc: Vec<String>,
 
+    /// Send as one of the account's `identities`, by address or the part before the `@`.
+    #[arg(long)]
+    from: Option<String>,
+
     /// Attach a file to the email.
     #[arg(short, long)]
-    attach: Vec<String>
-}
+    attach: Vec<String>,
+
+    /// Don't send, but write the email to this Maildir, or as a `.eml` file to this directory. Defaults to the temporary directory.
+    #[arg(long, value_name = "DIR", require_equals = true)]
+    dry_run: Option<Option<PathBuf>>,
+
+    /// Don't send, but print the email as it would be sent.
+    #[arg(long, conflicts_with = "dry_run")]
+    print: bool,
 
-fn get_config(account: String) -> Config {
-    let directories = AppDirs::new(Some(crate_name!()), false).unwrap();
+    /// With `--print`, decode text parts and headers so they're readable.
+    #[arg(long, requires = "print")]
+    decode: bool,
 
-    let config_file = directories.config_dir.join(account);
-    let toml = fs::read_to_string(config_file).expect("Couldn't read config file.");
+    /// Don't send now, but add the email to the queue for `sendmail queue flush`.
+    #[arg(long, conflicts_with_all = ["dry_run", "print"])]
+    queue: bool,
 
-    toml::from_str(&toml).expect("Failed to parse TOML.")        
+    /// Send the email at this local time, like `2026-10-16T09:00` or `09:00`, through `sendmail queue run`.
+    #[arg(long, value_name = "TIME", value_parser = time::parse_time, conflicts_with_all = ["dry_run", "print", "queue"])]
+    at: Option<u64>,
+
+    /// Send the email after this long, like `30m` or `2h`, through `sendmail queue run`.
+    #[arg(long = "in", value_name = "DURATION", value_parser = time::parse_duration, conflicts_with_all = ["dry_run", "print", "queue", "at"])]
+    after: Option<Duration>,
+
+    /// Send an email for every row in this CSV or JSON file, filling in `{{column}}` in the body, subject, recipients and attachment paths.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["dry_run", "print", "queue", "at", "after", "delay"])]
+    merge: Option<PathBuf>,
+
+    /// With `--merge`, print the email for this row (counting from 1) instead of sending anything.
+    #[arg(long, value_name = "ROW", requires = "merge")]
+    preview: Option<usize>,
+
+    /// Wait this long before sending, like `30s`, so it can still be cancelled. Overrides `undo_seconds`.
+    #[arg(long, value_name = "DURATION", value_parser = time::parse_duration, conflicts_with_all = ["dry_run", "print", "queue", "at", "after"])]
+    delay: Option<Duration>,
 }
 
 fn main() {
     let args = Args::parse();
-    let config = get_config(args.account);
+    let settings = config::get_settings(args.config);
+
+    match args.command {
+        Some(Command::CheckConfig { account }) => check::check_config(&settings, account),
+        Some(Command::Accounts) => list_accounts(&settings),
+        Some(Command::ShowConfig { account }) => show_config(&settings, account),
+        Some(Command::Quota { account }) => show_quota(&settings, account),
+        Some(Command::Queue { command }) => queue_command(&settings, command),
+        Some(Command::Cancel { ids }) => for id in ids {
+            match queue::remove(&id) {
+                true => println!("Cancelled {}.", id),
+                false => panic!("{}: Not waiting to be sent, it may have been sent already.", id),
+            }
+        },
+        Some(Command::Agent { ttl }) => agent::run(Duration::from_secs(ttl)),
+        Some(Command::Password { command: PasswordCommand::Set { account, password_stdin } }) => {
+            set_password(&settings, account, password_stdin)
+        }
+        None => send(&settings, args.send.unwrap()),
+    }
+}
+
+fn list_accounts(settings: &Settings) {
+    let accounts = settings.account_names();
+    let width = accounts.iter().map(String::len).max().unwrap_or(0);
+
+    for account in accounts {
+        let default = if settings.default_account.as_ref() == Some(&account) { "*" } else { " " };
+
+        let aliases: Vec<&str> = settings.aliases.iter()
+            .filter(|(_, target)| **target == account)
+            .map(|(alias, _)| alias.as_str())
+            .collect();
+
+        let aliases = if aliases.is_empty() { String::new() } else { format!(" ({})", aliases.join(", ")) };
+
+        let details = match settings.try_get_config(&account) {
+            Ok(config) => match &config.smtp {
+                Some(smtp) if config.transport == TransportKind::Smtp => format!("{} <{}> via {}:{}", config.name, config.email, smtp.hostname, smtp.port),
+                _ => format!("{} <{}> via {}", config.name, config.email, mta::Sendmail::new(config.sendmail_command.as_deref(), &config.sendmail_args).describe()),
+            },
+            Err(_) => match settings.extended_by(&account) {
+                extended_by if !extended_by.is_empty() => format!("shared settings, extended by {}", extended_by.join(", ")),
+                _ => "invalid config, run `sendmail check-config`".to_string(),
+            },
+        };
+
+        println!("{} {:width$}  {}{}", default, account, details, aliases);
+    }
+}
+
+fn show_config(settings: &Settings, account: Option<String>) {
+    let account = settings.resolve_account(account);
+    let table = settings.get_merged_table(&account).unwrap_or_else(|e| panic!("{}", e));
+
+    print!("{}", toml::to_string(&table).unwrap());
+}
+
+fn show_quota(settings: &Settings, account: Option<String>) {
+    let account = settings.resolve_account(account);
+    let config = settings.get_config(&account);
+    let usage = quota::usage(&account);
+
+    let of = |limit: Option<u32>| match limit {
+        Some(limit) => format!(" of {}", limit),
+        None => " (no limit)".to_string(),
+    };
 
-    let mail = create_mail(
-        args.path, 
-        args.subject, 
-        args.to, 
-        args.cc, 
-        args.bcc, 
-        args.attach, 
-        &config
-    );
+    println!("Last minute:    {}{}", usage.last_minute(), of(config.max_per_minute));
+    println!("Last 24 hours:  {}{}", usage.last_day(), of(config.max_per_day));
+}
+
+fn set_password(settings: &Settings, account: String, from_stdin: bool) {
+    let account = settings.resolve_account(Some(account));
+    let config = settings.get_config(&account);
+    let Some(smtp) = &config.smtp else { panic!("{}: Has no [smtp] section to store a password for.", account) };
+
+    if smtp.password_source != Some(PasswordSource::Keyring) {
+        eprintln!("Note: {} doesn't use the keyring yet, set `password_source = \"keyring\"` in its [smtp] section.", account);
+    }
 
-    send_mail(mail, args.password, &config)
+    let password = password::read_new_password(from_stdin, smtp);
+    keyring::store(&account, &password);
+
+    println!("Stored password for {} in the keyring.", account);
 }
 
-fn create_mail(path: String, subject: String, to: Vec<String>, cc: Vec<String>, bcc: Vec<String>, files: Vec<String>, config: &Config) -> Message {
-    let from = parse_address(format!("{} <{}>", config.name, config.email));
+fn send(settings: &Settings, mut args: SendArgs) {
+    // clap can't tell an account from the first of several paths, so the
+    // account positional may really be a path.
+    let account = match (args.account_flag.take(), args.account.take()) {
+        (Some(account), first_path) => {
+            args.paths.splice(0..0, first_path);
+            Some(account)
+        }
+        (None, Some(first_path)) if !is_account(settings, &first_path) && Path::new(&first_path).is_file() => {
+            args.paths.insert(0, first_path);
+            None
+        }
+        (None, account) => account,
+    };
+
+    let account = settings.resolve_account(account);
+    let config = settings.get_config(&account);
+    let identity = config.identity(args.from.as_deref()).unwrap_or_else(|e| panic!("{}", e));
+
+    if let Some(rows) = args.merge.clone() {
+        return send_merge(args, &rows, &account, &config, &identity);
+    }
+
+    let mails: Vec<Message> = args.paths.iter().map(|path| create_mail(
+        read_markdown(path),
+        args.subject.clone(),
+        args.to.clone(),
+        args.cc.clone(),
+        args.bcc.clone(),
+        args.attach.clone(),
+        &identity
+    )).collect();
+
+    if let Some(target) = args.dry_run {
+        return mails.iter().for_each(|mail| output::dry_run(mail, target.clone()));
+    }
+
+    if args.print {
+        return mails.iter().for_each(|mail| output::print(mail, args.decode));
+    }
+
+    let mut outgoing: Vec<(String, Envelope, Vec<u8>)> = args.paths.iter().zip(&mails)
+        .map(|(path, mail)| (path.clone(), mail.envelope().clone(), mail.formatted()))
+        .collect();
+
+    // Only name the file an outcome is about when there's more than one.
+    let several = outgoing.len() > 1;
+    let label = |path: &str| if several { format!("{}: ", path) } else { String::new() };
+
+    if args.queue {
+        for (path, envelope, message) in &outgoing {
+            let id = queue::add(&account, envelope, message, None);
+            println!("{}Queued as {}, send it with `sendmail queue flush`.", label(path), id);
+        }
+        return;
+    }
+
+    if let Some(send_at) = args.at.or(args.after.map(|after| time::now() + after.as_secs())) {
+        for (path, envelope, message) in &outgoing {
+            let id = queue::add(&account, envelope, message, Some(send_at));
+            println!("{}Scheduled as {} for {}.", label(path), id, time::format_time(send_at));
+        }
+        return println!("Make sure `sendmail queue run` runs regularly, from cron or a systemd timer.");
+    }
+
+    let credentials = config.smtp.as_ref()
+        .filter(|_| config.transport == TransportKind::Smtp)
+        .and_then(|smtp| credentials(&account, smtp, args.password.map(Secret::new), args.password_stdin));
+
+    let delay = args.delay.or(config.undo_seconds.map(Duration::from_secs)).unwrap_or_default();
+    if !delay.is_zero() {
+        // Through the queue, so `sendmail cancel` can find them, and they're not lost if we die while waiting.
+        let ids: Vec<String> = outgoing.iter()
+            .map(|(_, envelope, message)| queue::add(&account, envelope, message, Some(time::now().saturating_add(delay.as_secs()).saturating_add(ABANDONED_AFTER))))
+            .collect();
+
+        let claimed = wait_for_undo(&ids, delay);
+        let mut ids = ids.into_iter();
+        outgoing.retain(|_| claimed.contains(&ids.next().unwrap()));
+
+        if outgoing.is_empty() {
+            eprintln!("Cancelled, nothing was sent.");
+            process::exit(1)
+        }
+    }
+
+    let mut failed = false;
+    let batch = outgoing.iter().map(|(path, envelope, message)| ((path, envelope, message), envelope, message));
+
+    Mailer::new(&config, credentials).send_batch(batch, &account, |(path, envelope, message), result| match result {
+        Ok(()) => println!("{}Sent!", label(path)),
+        // Like a wrong password or a dropped connection, fixing that shouldn't mean writing the email again.
+        Err(e) if !e.rejected => {
+            let id = queue::add(&account, envelope, message, None);
+            eprintln!("{}Could not send email: {}\nQueued as {}, send it later with `sendmail queue flush`.", label(path), e.message, id);
+            failed = true;
+        }
+        Err(e) => {
+            eprintln!("{}Could not send email: {}", label(path), e.message);
+            failed = true;
+        }
+    });
+
+    if failed { process::exit(1) }
+}
+
+/// How long after the undo window `sendmail queue run` may send a delayed
+/// email itself, in case we didn't get to it.
+const ABANDONED_AFTER: u64 = 60;
+
+const UNDO_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Waits for `delay` unless the emails are cancelled with Ctrl-C, or one by
+/// one with `sendmail cancel`. Returns the IDs of the emails to go ahead and send.
+fn wait_for_undo(ids: &[String], delay: Duration) -> Vec<String> {
+    println!("Sending in {}s. Press Ctrl-C or run `sendmail cancel {}` to cancel.", delay.as_secs_f64().ceil(), ids.join(" "));
+
+    unsafe { libc::signal(libc::SIGINT, on_interrupt as *const () as libc::sighandler_t) };
+    let deadline = Instant::now() + delay;
+
+    let send = loop {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            ids.iter().for_each(|id| { queue::remove(id); });
+            break Vec::new();
+        }
+
+        if !ids.iter().any(|id| queue::contains(id)) { break Vec::new() }
+
+        // Taking them out of the queue makes sure nobody else sends them too.
+        if Instant::now() >= deadline {
+            break ids.iter().filter(|id| queue::remove(id)).cloned().collect();
+        }
+
+        thread::sleep(UNDO_POLL_INTERVAL);
+    };
+
+    unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+    send
+}
+
+/// Whether `name` is an account or an alias of one.
+fn is_account(settings: &Settings, name: &str) -> bool {
+    settings.locate(&settings.resolve_account(Some(name.to_string()))).is_ok()
+}
+
+/// Sends an email for every row in `rows`, over a single connection. Rows
+/// that were sent before, according to the progress file, are skipped.
+fn send_merge(args: SendArgs, rows_file: &Path, account: &str, config: &Config, identity: &Identity) {
+    let rows = merge::read_rows(rows_file);
+    let [path] = &args.paths[..] else { panic!("--merge takes a single body to fill in, got {} files.", args.paths.len()) };
+    let template = read_markdown(path);
+
+    let render_row = |row: &merge::Row| -> Result<Message, String> {
+        let render_all = |templates: &[String]| -> Result<Vec<String>, String> {
+            templates.iter().map(|template| merge::render(template, row)).collect()
+        };
+
+        let (to, cc, bcc, attach) = (render_all(&args.to)?, render_all(&args.cc)?, render_all(&args.bcc)?, render_all(&args.attach)?);
+
+        for address in to.iter().chain(&cc).chain(&bcc) {
+            address.parse::<Mailbox>().map_err(|_| format!("Malformed address: {}", address))?;
+        }
+
+        if let Some(file) = attach.iter().find(|file| !Path::new(file).is_file()) {
+            return Err(format!("{}: No such file.", file));
+        }
+
+        let subject = merge::render(&args.subject, row)?;
+        Ok(create_mail(merge::render(&template, row)?, subject, to, cc, bcc, attach, identity))
+    };
+
+    if let Some(number) = args.preview {
+        let row = rows.get(number.wrapping_sub(1))
+            .unwrap_or_else(|| panic!("{}: There's no row {}, rows go from 1 to {}.", rows_file.display(), number, rows.len()));
+
+        let mail = render_row(row).unwrap_or_else(|e| panic!("Row {}: {}", number, e));
+        return output::print(&mail, true);
+    }
+
+    let [to, cc, bcc, attach] = [&args.to, &args.cc, &args.bcc, &args.attach].map(|values| values.join("\n"));
+    let mut progress = merge::Progress::load(rows_file, &[&template, &args.subject, &to, &cc, &bcc, &attach]);
+    let pending: Vec<(usize, &merge::Row)> = rows.iter().enumerate()
+        .map(|(index, row)| (index + 1, row))
+        .filter(|(_, row)| !progress.is_sent(row))
+        .collect();
+
+    if pending.len() < rows.len() {
+        println!("Skipping {} rows that were already sent, according to {}.", rows.len() - pending.len(), progress.path().display());
+    }
+
+    let credentials = config.smtp.as_ref()
+        .filter(|_| config.transport == TransportKind::Smtp && !pending.is_empty())
+        .and_then(|smtp| credentials(account, smtp, args.password.map(Secret::new), args.password_stdin));
+
+    let mut failed = 0;
+    let mut unrenderable = 0;
+
+    let total = pending.len();
+
+    let batch = pending.into_iter()
+        .filter_map(|(number, row)| match render_row(row) {
+            Ok(mail) => Some(((number, row, mail.envelope().to().to_vec()), mail.envelope().clone(), mail.formatted())),
+            Err(e) => {
+                eprintln!("Row {}: Could not send email: {}", number, e);
+                unrenderable += 1;
+                None
+            }
+        });
+
+    Mailer::new(config, credentials).send_batch(batch, account, |(number, row, to), result| match result {
+        Ok(()) => {
+            progress.mark_sent(row);
+            let to: Vec<String> = to.iter().map(ToString::to_string).collect();
+            println!("Row {}: Sent to {}.", number, to.join(", "));
+        }
+        Err(e) => {
+            eprintln!("Row {}: Could not send email: {}", number, e.message);
+            failed += 1;
+        }
+    });
+
+    let failed = failed + unrenderable;
+    println!("Sent {} of {} emails.", total - failed, total);
+
+    if failed > 0 {
+        eprintln!("Run the same command again to retry the {} that failed.", failed);
+        process::exit(1)
+    }
+
+    progress.finish();
+}
+
+/// Logs in with a username and password (or OAuth2 token), unless the server
+/// doesn't want us to log in at all.
+fn credentials(account: &str, smtp: &ServerConfig, password: Option<Secret>, from_stdin: bool) -> Option<Credentials> {
+    let username = smtp.username.as_ref()?;
+
+    let password = || password::get_password(password, from_stdin, account, smtp);
+    let secret = match smtp.auth {
+        AuthMethod::Password => password(),
+        AuthMethod::OAuth2 => oauth::access_token(account, smtp, password),
+    };
+
+    Some(Credentials::new(username.clone(), secret.expose().to_string()))
+}
+
+fn queue_command(settings: &Settings, command: QueueCommand) {
+    match command {
+        QueueCommand::List => list_queue(),
+        QueueCommand::Flush { ids } => flush_queue(settings, ids),
+        QueueCommand::Run => flush_queue(settings, Vec::new()),
+        QueueCommand::Rm { ids } => {
+            for id in ids {
+                if !queue::remove(&id) { panic!("{}: Not in the queue.", id) }
+            }
+        }
+    }
+}
+
+fn list_queue() {
+    let entries = queue::list();
+    if entries.is_empty() { return println!("The queue is empty.") }
+
+    for (id, entry) in entries {
+        let attempts = match &entry.last_error {
+            Some(error) => format!(" ({} failed attempts, last: {})", entry.attempts, error),
+            None => String::new(),
+        };
+
+        let when = match entry.send_at {
+            _ if entry.held => "held, the server rejected it".to_string(),
+            Some(send_at) if !entry.is_due() => format!("scheduled for {}", time::format_time(send_at)),
+            _ => format!("{} ago", time::age(entry.queued_at)),
+        };
+
+        println!("{}  {}  {}  to {}{}", id, entry.account, when, entry.to.join(", "), attempts);
+    }
+}
+
+/// Tries to send the emails in `ids`, or everything that's due, each with the
+/// account it was written for. The emails of an account are sent together, over
+/// the same connection.
+fn flush_queue(settings: &Settings, ids: Vec<String>) {
+    let mut failed = false;
+
+    let entries = queue::list();
+    for id in ids.iter().filter(|id| !entries.iter().any(|(queued, _)| queued == *id)) {
+        eprintln!("{}: Not in the queue.", id);
+        failed = true;
+    }
+
+    let selected = entries.into_iter().filter(|(id, entry)| match ids.is_empty() {
+        true => entry.is_due(),
+        false => ids.contains(id),
+    });
+
+    let mut by_account: Vec<(String, Vec<(String, queue::Entry)>)> = Vec::new();
+    for (id, entry) in selected {
+        match by_account.iter_mut().find(|(account, _)| *account == entry.account) {
+            Some((_, entries)) => entries.push((id, entry)),
+            None => by_account.push((entry.account.clone(), vec![(id, entry)])),
+        }
+    }
+
+    for (account, entries) in by_account {
+        let config = match settings.try_get_config(&account) {
+            Ok(config) => config,
+            Err(e) => {
+                entries.iter().for_each(|(id, _)| eprintln!("{}: {}", id, e));
+                failed = true;
+                continue;
+            }
+        };
+
+        let credentials = config.smtp.as_ref()
+            .filter(|_| config.transport == TransportKind::Smtp)
+            .and_then(|smtp| credentials(&account, smtp, None, false));
+
+        // Another run may be sending some of these already, those are left to it.
+        let batch = entries.into_iter().filter_map(|(id, _)| {
+            let entry = queue::claim(&id)?;
+            let (envelope, message) = (entry.envelope(), queue::message(&id));
+            Some(((id, entry), envelope, message))
+        });
+
+        Mailer::new(&config, credentials).send_batch(batch, &account, |(id, mut entry), result| match result {
+            Ok(()) => {
+                queue::finish(&id);
+                println!("{}: Sent!", id);
+            }
+            Err(e) => {
+                entry.attempts += 1;
+                entry.last_error = Some(e.message.lines().next().unwrap_or_default().to_string());
+                entry.held = e.rejected;
+                queue::release(&id, &entry);
+
+                eprintln!("{}: Could not send email: {}", id, e.message);
+                failed = true;
+            }
+        });
+    }
+
+    if failed { process::exit(1) }
+}
+
+#[derive(Clone)]
+struct SendError {
+    message: String,
+    /// Whether the server refused the message itself, so sending it again
+    /// won't help. Anything else, like a rejected login, can be fixed.
+    rejected: bool,
+    /// Whether every other email would fail the same way, like when the
+    /// server rejects the login.
+    fatal: bool,
+}
+
+/// The transport of an account, set up once to send any number of emails.
+/// SMTP connections are reused between them.
+struct Mailer<'a> {
+    config: &'a Config,
+    connection: Connection<'a>,
+}
+
+enum Connection<'a> {
+    Smtp(SmtpTransport),
+    /// Checks `pin_sha256` on the connection it sends over.
+    Pinned(Box<tls::PinnedTransport<'a>>),
+    /// Hands messages to the local MTA, which takes care of delivering them.
+    Sendmail(mta::Sendmail),
+}
+
+impl<'a> Mailer<'a> {
+    fn new(config: &'a Config, credentials: Option<Credentials>) -> Self {
+        let connection = match (config.transport, &config.smtp) {
+            (TransportKind::Sendmail, _) => Connection::Sendmail(mta::Sendmail::new(config.sendmail_command.as_deref(), &config.sendmail_args)),
+            (TransportKind::Smtp, Some(smtp)) if smtp.pin_sha256.is_some() => {
+                Connection::Pinned(Box::new(tls::PinnedTransport::new(smtp, credentials, auth_mechanisms(smtp))))
+            }
+            (TransportKind::Smtp, smtp) => Connection::Smtp(create_smtp_mailer(smtp.as_ref().unwrap(), credentials)),
+        };
+
+        Mailer { config, connection }
+    }
+
+    /// Sends a single email, within the `max_per_minute` and `max_per_day` of the account.
+    fn send(&self, envelope: &Envelope, message: &[u8], account: &str) -> Result<(), SendError> {
+        // Trying again tomorrow works, but not for anything else in this batch.
+        quota::wait_for_turn(account, self.config)
+            .map_err(|message| SendError { message, rejected: false, fatal: true })?;
+
+        match &self.connection {
+            Connection::Smtp(mailer) => send_mail(mailer, envelope, message, account, self.config.smtp.as_ref().unwrap())?,
+            Connection::Pinned(mailer) => send_mail(mailer.as_ref(), envelope, message, account, self.config.smtp.as_ref().unwrap())?,
+            Connection::Sendmail(mailer) => mailer.send(envelope, message)
+                .map_err(|message| SendError { message, rejected: false, fatal: false })?,
+        }
+
+        quota::record(account);
+        Ok(())
+    }
+
+    /// Sends a batch of emails one after the other, reusing connections, and
+    /// calls `report` with how each one went. A failed email doesn't stop the
+    /// rest, unless they would all fail the same way.
+    fn send_batch<T, E, M>(&self, batch: impl IntoIterator<Item = (T, E, M)>, account: &str, mut report: impl FnMut(T, Result<(), SendError>))
+    where
+        E: Borrow<Envelope>,
+        M: AsRef<[u8]>,
+    {
+        let mut fatal: Option<SendError> = None;
+
+        for (item, envelope, message) in batch {
+            let result = match &fatal {
+                // Don't try the same password over and over, servers lock accounts for that.
+                Some(e) => Err(e.clone()),
+                None => self.send(envelope.borrow(), message.as_ref(), account),
+            };
+
+            if let Err(e @ SendError { fatal: true, .. }) = &result { fatal = Some(e.clone()) }
+            report(item, result);
+        }
+    }
+}
+
+fn create_mail(markdown: String, subject: String, to: Vec<String>, cc: Vec<String>, bcc: Vec<String>, files: Vec<String>, identity: &Identity) -> Message {
+    let from = parse_address(format!("{} <{}>", identity.name.as_deref().unwrap_or_default(), identity.email));
     
     let to: To = addresses(to).into();
     let cc: Cc = addresses(cc).into();
     let bcc: Bcc = addresses(bcc).into();
 
-    let (plain, html) = parse_markdown(path);
+    let (plain, html) = parse_markdown(markdown, identity.signature.as_deref());
 
     let body = MultiPart::alternative_plain_html(plain, html);
     let mut content = MultiPart::mixed().multipart(body);
@@ -113,8 +760,12 @@ fn create_mail(path: String, subject: String, to: Vec<String>, cc: Vec<String>,
         content = content.singlepart(attachment);
     };
 
-    Message::builder()
-        .from(from)
+    let mut message = Message::builder().from(from);
+    if let Some(reply_to) = &identity.reply_to {
+        message = message.reply_to(parse_address(reply_to.clone()));
+    }
+
+    message
         .subject(subject)
         .mailbox(to)
         .mailbox(cc)
@@ -137,25 +788,34 @@ fn create_attachment(path: String) -> SinglePart {
     validate_file(&path);
 
     let basename = Path::new(&path).file_name().unwrap().to_str().unwrap().to_string();
-    let body = fs::read(&path).expect(&format!("{}: Couldn't read file.", path));
+    let body = fs::read(&path).unwrap_or_else(|_| panic!("{}: Couldn't read file.", path));
 
     // Try to infer the mime type and otherwise fall back to application/octet-stream
     let mime_type = mime_guess::from_path(&path).first().unwrap_or(mime::APPLICATION_OCTET_STREAM);
-    let content_type = ContentType::parse(&mime_type.to_string()).unwrap();
+    let content_type = ContentType::parse(mime_type.as_ref()).unwrap();
     
     Attachment::new(basename).body(body, content_type)
 }
 
 fn parse_address(address: String) -> Mailbox {
-    address.parse().expect(&format!("Malformed address: {}", address))
+    address.parse().unwrap_or_else(|_| panic!("Malformed address: {}", address))
 }
 
-fn parse_markdown(path: String) -> (String, String) {
-    validate_file(&path);
+fn read_markdown(path: &str) -> String {
+    validate_file(path);
+    fs::read_to_string(path).unwrap_or_else(|_| panic!("{}: Couldn't read file.", path))
+}
 
-    let plain = fs::read_to_string(&path).expect(&format!("{}: Couldn't read file.", path));
-    let html = markdown::to_html(&plain);
+fn parse_markdown(mut plain: String, signature: Option<&str>) -> (String, String) {
+    let mut markdown = plain.clone();
+
+    if let Some(signature) = signature {
+        plain = format!("{}\n\n-- \n{}\n", plain.trim_end(), signature.trim_end());
+        // Two trailing spaces make a line break, so the delimiter stays on its own line.
+        markdown = format!("{}\n\n--  \n{}\n", markdown.trim_end(), signature.trim_end());
+    }
 
+    let html = markdown::to_html(&markdown);
     (plain, html)
 }
 
@@ -165,15 +825,201 @@ fn validate_file(path: &str) {
     if !file.is_file() { panic!("{}: Not a file.", path) }
 }
 
-fn send_mail(mail: Message, password: String, config: &Config) {
-    let credentials = Credentials::new(config.smtp.username.clone(), password);
-    let mailer = SmtpTransport::relay(&config.smtp.hostname)
-        .unwrap()
-        .credentials(credentials)
-        .build();
+fn create_smtp_mailer(smtp: &ServerConfig, credentials: Option<Credentials>) -> SmtpTransport {
+    let mut mailer = create_transport(smtp);
+
+    if let Some(credentials) = credentials {
+        mailer = mailer
+            .credentials(credentials)
+            .authentication(auth_mechanisms(smtp));
+    }
+
+    mailer.build()
+}
+
+fn auth_mechanisms(smtp: &ServerConfig) -> Vec<Mechanism> {
+    smtp.auth_mechanisms().into_iter().map(|mechanism| match mechanism {
+        AuthMechanism::Plain => Mechanism::Plain,
+        AuthMechanism::Login => Mechanism::Login,
+        AuthMechanism::Xoauth2 => Mechanism::Xoauth2,
+    }).collect()
+}
+
+fn send_mail(mailer: &impl Transport<Error = lettre::transport::smtp::Error>, envelope: &Envelope, message: &[u8], account: &str, smtp: &ServerConfig) -> Result<(), SendError> {
+    let attempts = smtp.retries + 1;
+    let mut backoff = smtp.retry_backoff;
+
+    for attempt in 1..=attempts {
+        let e = match mailer.send_raw(envelope, message) {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+
+        if attempts > 1 {
+            eprintln!("Attempt {}/{} failed: {}", attempt, attempts, e);
+        }
+
+        if is_auth_failure(&e) {
+            // Don't keep handing out a password or token the server doesn't accept.
+            agent::forget(account);
+            if smtp.auth == AuthMethod::OAuth2 { oauth::forget(account) }
+        }
+
+        if tls::is_handshake_failure(&e) {
+            return Err(SendError { message: format!("{e}\n\n{}", tls::describe_chain(smtp)), rejected: false, fatal: true });
+        }
+
+        if attempt == attempts || !is_transient_failure(&e) {
+            let rejected = is_rejection(&e);
+            return Err(SendError { message: e.to_string(), rejected, fatal: is_auth_failure(&e) });
+        }
+
+        eprintln!("Retrying in {:.1}s...", backoff);
+        thread::sleep(Duration::from_secs_f64(backoff));
+        backoff *= 2.0;
+    }
+
+    unreachable!()
+}
+
+/// Whether the server rejected the credentials (530, 534 or 535).
+fn is_auth_failure(error: &lettre::transport::smtp::Error) -> bool {
+    error.status().is_some_and(|code| code.to_string().starts_with("53"))
+}
+
+/// Whether the server refused the message itself, with a 5xx reply that isn't
+/// about logging in.
+fn is_rejection(error: &lettre::transport::smtp::Error) -> bool {
+    error.is_permanent() && !is_auth_failure(error)
+}
+
+/// Whether trying again later might work: a 4xx reply, or the connection
+/// dropping or timing out. Rejections (5xx) and configuration problems aren't.
+fn is_transient_failure(error: &lettre::transport::smtp::Error) -> bool {
+    if error.is_transient() || error.is_timeout() { return true }
+    if error.is_permanent() { return false }
+
+    let mut source = error.source();
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<io::Error>() {
+            return matches!(e.kind(),
+                io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::TimedOut
+                // Socket read/write timeouts show up as EAGAIN on Linux.
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::Interrupted
+            );
+        }
+
+        source = e.source();
+    }
+
+    false
+}
+
+fn create_transport(server: &ServerConfig) -> SmtpTransportBuilder {
+    let mut transport = SmtpTransport::builder_dangerous(&server.hostname)
+        .port(server.port)
+        .tls(tls::create_tls(server));
+
+    if let Some(timeout) = server.timeout_secs {
+        transport = transport.timeout(Some(Duration::from_secs(timeout)));
+    }
+
+    transport
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    use lettre::transport::smtp::client::SmtpConnection;
+    use lettre::transport::smtp::extension::ClientId;
+
+    use super::*;
+
+    /// Sends an email to a local server that answers `MAIL FROM` with `reply`,
+    /// or doesn't answer at all without one, and returns how that failed.
+    fn send_error(reply: Option<&'static str>) -> lettre::transport::smtp::Error {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+            let _ = stream.write_all(b"220 test\r\n");
+
+            while let Some(Ok(line)) = lines.next() {
+                let answer = match line.get(..4).unwrap_or_default() {
+                    "EHLO" => "250 test",
+                    "MAIL" => match reply {
+                        Some(reply) => reply,
+                        None => { thread::sleep(Duration::from_secs(5)); return }
+                    },
+                    _ => "221 bye",
+                };
+
+                if stream.write_all(format!("{}\r\n", answer).as_bytes()).is_err() { return }
+            }
+        });
+
+        // Only wait briefly when the server isn't going to answer.
+        send_to("127.0.0.1", port, Duration::from_secs(if reply.is_some() { 30 } else { 1 }))
+    }
+
+    // A plain connection rather than an `SmtpTransport`, whose pool opens a
+    // connection of its own in the background.
+    fn send_to(host: &str, port: u16, timeout: Duration) -> lettre::transport::smtp::Error {
+        let envelope = Envelope::new(Some("a@example.com".parse().unwrap()), vec!["b@example.com".parse().unwrap()]).unwrap();
+
+        SmtpConnection::connect((host, port), Some(timeout), &ClientId::default(), None, None)
+            .and_then(|mut connection| connection.send(&envelope, b"Subject: Test\r\n\r\nHi\r\n"))
+            .unwrap_err()
+    }
+
+    #[test]
+    fn retries_temporary_failures() {
+        let e = send_error(Some("451 Try again later"));
+        assert!(is_transient_failure(&e));
+        assert!(!is_rejection(&e) && !is_auth_failure(&e));
+    }
+
+    #[test]
+    fn gives_up_on_rejections() {
+        let e = send_error(Some("550 No such user"));
+        assert!(!is_transient_failure(&e));
+        assert!(is_rejection(&e) && !is_auth_failure(&e));
+    }
+
+    #[test]
+    fn tells_failed_logins_from_rejections() {
+        for reply in ["530 Authentication required", "535 Bad credentials"] {
+            let e = send_error(Some(reply));
+            assert!(is_auth_failure(&e), "{}", reply);
+            assert!(!is_transient_failure(&e) && !is_rejection(&e), "{}", reply);
+        }
+    }
+
+    #[test]
+    fn retries_connection_failures() {
+        // Nothing listens on a port that was just freed. The other tests only
+        // listen on 127.0.0.1, so they can't take it in the meantime.
+        let port = TcpListener::bind("127.0.0.2:0").unwrap().local_addr().unwrap().port();
+        let e = send_to("127.0.0.2", port, Duration::from_secs(30));
+        assert!(is_transient_failure(&e));
+        assert!(!is_rejection(&e) && !is_auth_failure(&e));
+    }
 
-    match mailer.send(&mail) {
-        Ok(_) => println!("Sent!"),
-        Err(e) => panic!("Could not send email: {e:?}"),
+    #[test]
+    fn retries_timeouts() {
+        let e = send_error(None);
+        assert!(is_transient_failure(&e), "{:?}", e);
+        assert!(!is_rejection(&e) && !is_auth_failure(&e));
     }
 }
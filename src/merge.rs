@@ -0,0 +1,288 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::json;
+
+/// The values for one email in a mail merge, by column name.
+pub type Row = HashMap<String, String>;
+
+/// Reads the rows of a mail merge from a CSV file with a header row, or from
+/// a JSON file with an array of objects.
+pub fn read_rows(path: &Path) -> Vec<Row> {
+    let contents = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("{}: Couldn't read file: {e}", path.display()));
+
+    let rows = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => json::parse_objects(&contents)
+            .unwrap_or_else(|| panic!("{}: Expected a JSON array of objects.", path.display())),
+        _ => parse_csv(&contents).unwrap_or_else(|e| panic!("{}: {}", path.display(), e)),
+    };
+
+    if rows.is_empty() { panic!("{}: No rows to send.", path.display()) }
+    rows
+}
+
+fn parse_csv(contents: &str) -> Result<Vec<Row>, String> {
+    let mut records = csv_records(contents.trim_start_matches('\u{feff}'))?.into_iter();
+    let header = records.next().ok_or("Missing header row.")?;
+
+    let rows = records
+        // Trailing empty lines aren't rows.
+        .filter(|record| record.iter().any(|field| !field.is_empty()))
+        .map(|record| header.iter().cloned().zip(record.into_iter().chain(std::iter::repeat(String::new()))).collect())
+        .collect();
+
+    Ok(rows)
+}
+
+/// Splits CSV into records and fields, handling quoted fields with commas,
+/// line breaks and `""` in them.
+fn csv_records(contents: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = contents.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.next_if_eq(&'"').is_some() => field.push('"'),
+            ('"', true) => quoted = false,
+            ('"', false) if field.is_empty() => quoted = true,
+            (',', false) => record.push(std::mem::take(&mut field)),
+            ('\r', false) if chars.peek() == Some(&'\n') => {}
+            ('\n', false) => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            (c, _) => field.push(c),
+        }
+    }
+
+    if quoted { return Err("Unterminated quoted field.".to_string()) }
+
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+/// Replaces every `{{column}}` in `template` with its value in `row`.
+pub fn render(template: &str, row: &Row) -> Result<String, String> {
+    let mut output = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..].find("}}").ok_or("Unclosed `{{`.")? + start;
+        let column = rest[start + 2..end].trim();
+
+        let value = row.get(column).ok_or_else(|| {
+            let mut columns: Vec<&str> = row.keys().map(String::as_str).collect();
+            columns.sort();
+            format!("Unknown column `{}`, expected one of: {}", column, columns.join(", "))
+        })?;
+
+        output += &rest[..start];
+        output += value;
+        rest = &rest[end + 2..];
+    }
+
+    Ok(output + rest)
+}
+
+/// Keeps track of the rows that were sent, in a file next to the rows, so an
+/// interrupted merge can be picked up where it left off.
+///
+/// The file starts with a fingerprint of the templates, so sending the same
+/// rows with a different email starts over. Rows are recorded by a fingerprint
+/// of their contents rather than their position, so rows can be added, removed
+/// or reordered in between.
+pub struct Progress {
+    path: PathBuf,
+    header: String,
+    /// Whether the file on disk belongs to these templates, rather than to
+    /// an earlier merge that should be overwritten.
+    current: bool,
+    sent: BTreeSet<u64>,
+}
+
+impl Progress {
+    pub fn load(rows: &Path, templates: &[&str]) -> Self {
+        let mut path = rows.as_os_str().to_owned();
+        path.push(".progress");
+        let path = PathBuf::from(path);
+
+        let header = format!("# {:016x}", fingerprint(templates));
+        let contents = fs::read_to_string(&path).unwrap_or_default();
+        let mut lines = contents.lines();
+        let current = lines.next() == Some(header.as_str());
+
+        let sent = match current {
+            true => lines.filter_map(|line| u64::from_str_radix(line.trim(), 16).ok()).collect(),
+            false => BTreeSet::new(),
+        };
+
+        Progress { path, header, current, sent }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_sent(&self, row: &Row) -> bool {
+        self.sent.contains(&row_fingerprint(row))
+    }
+
+    pub fn mark_sent(&mut self, row: &Row) {
+        let row = row_fingerprint(row);
+        let line = match self.current {
+            true => format!("{:016x}\n", row),
+            false => format!("{}\n{:016x}\n", self.header, row),
+        };
+
+        let mut file = match self.current {
+            true => fs::File::options().append(true).open(&self.path),
+            false => fs::File::create(&self.path),
+        }.unwrap_or_else(|e| panic!("{}: Couldn't write progress: {e}", self.path.display()));
+
+        file.write_all(line.as_bytes())
+            .unwrap_or_else(|e| panic!("{}: Couldn't write progress: {e}", self.path.display()));
+
+        self.current = true;
+
+        self.sent.insert(row);
+    }
+
+    /// Forgets the progress, once every row was sent.
+    pub fn finish(self) {
+        if self.current { let _ = fs::remove_file(&self.path); }
+    }
+}
+
+/// Fingerprint of a row's columns and values, whatever order they're in.
+fn row_fingerprint(row: &Row) -> u64 {
+    let mut fields: Vec<(&String, &String)> = row.iter().collect();
+    fields.sort();
+
+    fingerprint(&fields.iter().flat_map(|(column, value)| [column.as_str(), value.as_str()]).collect::<Vec<_>>())
+}
+
+/// FNV-1a, which unlike the standard library's hasher is the same everywhere
+/// and in every Rust version, so progress files stay valid.
+fn fingerprint(templates: &[&str]) -> u64 {
+    templates.iter()
+        .flat_map(|template| template.bytes().chain([0]))
+        .fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn row(fields: &[(&str, &str)]) -> Row {
+        fields.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn splits_csv_records() {
+        let records = csv_records("a,b,c\n1,,3\n").unwrap();
+        assert_eq!(records, [["a", "b", "c"], ["1", "", "3"]]);
+    }
+
+    #[test]
+    fn handles_quoted_fields() {
+        let records = csv_records("\"Boers, Robin\",\"say \"\"hi\"\"\",\"two\nlines\"\n").unwrap();
+        assert_eq!(records, [["Boers, Robin", "say \"hi\"", "two\nlines"]]);
+    }
+
+    #[test]
+    fn handles_crlf_and_missing_final_newline() {
+        let records = csv_records("a,b\r\n1,2\r\n3,4").unwrap();
+        assert_eq!(records, [["a", "b"], ["1", "2"], ["3", "4"]]);
+
+        // Line breaks in quoted fields are kept as they are.
+        assert_eq!(csv_records("\"x\r\ny\"").unwrap(), [["x\r\ny"]]);
+    }
+
+    #[test]
+    fn rejects_unterminated_quotes() {
+        assert!(csv_records("a,\"b\n1,2\n").is_err());
+    }
+
+    #[test]
+    fn parses_csv_rows_by_header() {
+        let rows = parse_csv("\u{feff}name,email\nAlice,alice@example.com\nBob\n\n").unwrap();
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0], row(&[("name", "Alice"), ("email", "alice@example.com")]));
+        // Missing fields are empty, and the empty line at the end isn't a row.
+        assert_eq!(rows[1], row(&[("name", "Bob"), ("email", "")]));
+
+        assert!(parse_csv("").is_err());
+    }
+
+    #[test]
+    fn renders_columns() {
+        let row = row(&[("name", "Alice"), ("grade", "8.5")]);
+
+        assert_eq!(render("Hi {{name}}, you got {{ grade }}.", &row).unwrap(), "Hi Alice, you got 8.5.");
+        assert_eq!(render("No columns", &row).unwrap(), "No columns");
+        assert_eq!(render("{{name}}{{name}}", &row).unwrap(), "AliceAlice");
+        // Values aren't templates themselves.
+        assert_eq!(render("{{x}}", &self::row(&[("x", "{{x}}")])).unwrap(), "{{x}}");
+    }
+
+    #[test]
+    fn reports_unknown_columns_and_unclosed_braces() {
+        let row = row(&[("name", "Alice"), ("email", "a@example.com")]);
+
+        assert_eq!(render("{{nmae}}", &row).unwrap_err(), "Unknown column `nmae`, expected one of: email, name");
+        assert_eq!(render("Hi {{name", &row).unwrap_err(), "Unclosed `{{`.");
+    }
+
+    #[test]
+    fn progress_belongs_to_the_templates() {
+        let rows = env::temp_dir().join(format!("sendmail-test-{}-templates.csv", std::process::id()));
+        let (alice, bob) = (row(&[("name", "Alice")]), row(&[("name", "Bob")]));
+
+        let mut progress = Progress::load(&rows, &["body", "subject"]);
+        progress.mark_sent(&alice);
+        progress.mark_sent(&bob);
+
+        let progress = Progress::load(&rows, &["body", "subject"]);
+        assert!(progress.is_sent(&alice) && progress.is_sent(&bob));
+        assert!(!Progress::load(&rows, &["other body", "subject"]).is_sent(&alice));
+        // The separator keeps the templates apart.
+        assert!(!Progress::load(&rows, &["bodys", "ubject"]).is_sent(&alice));
+
+        Progress::load(&rows, &["body", "subject"]).finish();
+        assert!(!progress.path().exists());
+    }
+
+    #[test]
+    fn progress_follows_rows_that_moved() {
+        let rows = env::temp_dir().join(format!("sendmail-test-{}-moved.csv", std::process::id()));
+        let alice = row(&[("name", "Alice"), ("email", "alice@example.com")]);
+        let bob = row(&[("name", "Bob"), ("email", "bob@example.com")]);
+
+        let mut progress = Progress::load(&rows, &["body"]);
+        progress.mark_sent(&alice);
+
+        // Whatever the position of the row, or the order of its columns.
+        let progress = Progress::load(&rows, &["body"]);
+        assert!(progress.is_sent(&row(&[("email", "alice@example.com"), ("name", "Alice")])));
+        assert!(!progress.is_sent(&bob));
+        // An edited row is a different email.
+        assert!(!progress.is_sent(&row(&[("name", "Alice"), ("email", "alice@example.org")])));
+        // Columns and values don't run into each other.
+        assert!(!progress.is_sent(&row(&[("nameA", "lice"), ("email", "alice@example.com")])));
+
+        progress.finish();
+    }
+}
@@ -0,0 +1,57 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use lettre::address::Envelope;
+
+/// The binary to run when the account doesn't set `sendmail_command`.
+const DEFAULT_COMMAND: &str = "sendmail";
+
+/// Hands messages to a local sendmail-compatible binary, such as Postfix or
+/// msmtp, which takes care of delivering them. lettre's `SendmailTransport`
+/// can't pass arguments of its own, like msmtp's `-a <account>`.
+pub struct Sendmail {
+    command: String,
+    args: Vec<String>,
+}
+
+impl Sendmail {
+    pub fn new(command: Option<&str>, args: &[String]) -> Self {
+        Sendmail { command: command.unwrap_or(DEFAULT_COMMAND).to_string(), args: args.to_vec() }
+    }
+
+    /// Runs the command like `sendmail <args...> -i -f <from> -- <recipients...>`,
+    /// with the message on stdin.
+    pub fn send(&self, envelope: &Envelope, message: &[u8]) -> Result<(), String> {
+        let mut command = Command::new(&self.command);
+        command.args(&self.args).arg("-i");
+
+        if let Some(from) = envelope.from() {
+            command.arg("-f").arg(from);
+        }
+
+        let mut process = command
+            .arg("--")
+            .args(envelope.to())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Couldn't run `{}`: {e}", self.command))?;
+
+        // Closing stdin right after writing tells it the message is complete.
+        let written = process.stdin.take().unwrap().write_all(message);
+        let output = process.wait_with_output().map_err(|e| format!("Couldn't run `{}`: {e}", self.command))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("`{}` failed ({}): {}", self.command, output.status, stderr.trim()));
+        }
+
+        written.map_err(|e| format!("Couldn't hand the message to `{}`: {e}", self.command))
+    }
+
+    /// The command line, for showing where an account sends through.
+    pub fn describe(&self) -> String {
+        [&self.command].into_iter().chain(&self.args).cloned().collect::<Vec<_>>().join(" ")
+    }
+}
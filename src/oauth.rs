@@ -0,0 +1,346 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::crate_name;
+use platform_dirs::AppDirs;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::json;
+use crate::password::Secret;
+use crate::ServerConfig;
+
+/// Refresh the access token this many seconds before it actually expires,
+/// so it doesn't run out halfway through a slow SMTP session.
+const EXPIRY_MARGIN: u64 = 60;
+
+/// How long to wait for the token endpoint to connect or respond, so a server
+/// that stops answering doesn't hang a `queue run` from cron forever.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize)]
+struct CachedToken {
+    access_token: String,
+    expires_at: u64,
+}
+
+/// Returns a valid access token for `account`, either from the cache or by
+/// exchanging the refresh token from `refresh_token` at the token endpoint.
+pub fn access_token(account: &str, server: &ServerConfig, refresh_token: impl FnOnce() -> Secret) -> Secret {
+    cached_access_token(&cache_file(account), server, refresh_token)
+}
+
+/// Same as `access_token`, with the token cached in `cache_file`.
+fn cached_access_token(cache_file: &PathBuf, server: &ServerConfig, refresh_token: impl FnOnce() -> Secret) -> Secret {
+    if let Some(token) = read_cache(cache_file) {
+        return token;
+    }
+
+    let (token, expires_in) = refresh(server, refresh_token());
+    write_cache(cache_file, &CachedToken {
+        access_token: token.expose().to_string(),
+        expires_at: now() + expires_in,
+    });
+
+    token
+}
+
+/// Drops the cached access token of `account`, such as after the server
+/// rejected it, so the next send gets a new one.
+pub fn forget(account: &str) {
+    let _ = fs::remove_file(cache_file(account));
+}
+
+fn refresh(server: &ServerConfig, refresh_token: Secret) -> (Secret, u64) {
+    let endpoint = server.token_endpoint.as_deref().unwrap();
+    let client_id = server.client_id.as_deref().unwrap();
+
+    let mut form = url::form_urlencoded::Serializer::new(String::new());
+    form.append_pair("grant_type", "refresh_token");
+    form.append_pair("refresh_token", refresh_token.expose());
+    form.append_pair("client_id", client_id);
+    if let Some(secret) = &server.client_secret {
+        form.append_pair("client_secret", secret);
+    }
+
+    let body = Secret::new(form.finish());
+    let (status, response) = post(endpoint, body.expose());
+    let fields = json::parse_object(&response)
+        .unwrap_or_else(|| panic!("{}: Token endpoint returned invalid JSON (HTTP {}).", endpoint, status));
+
+    if let Some(error) = fields.get("error").filter(|error| !error.is_empty()) {
+        let description = fields.get("error_description").map(String::as_str).unwrap_or("no description");
+        panic!("{}: Couldn't refresh access token: {} ({}).", endpoint, error, description)
+    }
+
+    if !(200..300).contains(&status) {
+        panic!("{}: Couldn't refresh access token (HTTP {}).", endpoint, status)
+    }
+
+    let token = fields.get("access_token")
+        .unwrap_or_else(|| panic!("{}: Token endpoint didn't return an access token.", endpoint));
+
+    // Tokens without an expiry are only used once.
+    let expires_in = fields.get("expires_in")
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(EXPIRY_MARGIN);
+
+    (Secret::new(token.clone()), expires_in)
+}
+
+fn cache_file(account: &str) -> PathBuf {
+    let directories = AppDirs::new(Some(crate_name!()), false).unwrap();
+    directories.cache_dir.join("tokens").join(account)
+}
+
+fn read_cache(path: &PathBuf) -> Option<Secret> {
+    let contents = Secret::new(fs::read_to_string(path).ok()?);
+    let cached: CachedToken = toml::from_str(contents.expose()).ok()?;
+    let token = Secret::new(cached.access_token);
+
+    if cached.expires_at <= now() + EXPIRY_MARGIN { return None }
+
+    Some(token)
+}
+
+fn write_cache(path: &PathBuf, token: &CachedToken) {
+    let contents = Secret::new(toml::to_string(token).unwrap());
+    fs::create_dir_all(path.parent().unwrap())
+        .unwrap_or_else(|e| panic!("{}: Couldn't create cache directory: {e}", path.display()));
+
+    let mut file = fs::File::options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .unwrap_or_else(|e| panic!("{}: Couldn't write token cache: {e}", path.display()));
+
+    file.write_all(contents.expose().as_bytes())
+        .unwrap_or_else(|e| panic!("{}: Couldn't write token cache: {e}", path.display()));
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Sends a form-encoded POST request and returns the status code and body.
+fn post(endpoint: &str, body: &str) -> (u16, String) {
+    let url = Url::parse(endpoint).unwrap_or_else(|_| panic!("{}: Malformed token endpoint.", endpoint));
+    let host = url.host_str().unwrap_or_else(|| panic!("{}: Token endpoint has no host.", endpoint));
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nAccept: application/json\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        &url[url::Position::BeforePath..url::Position::AfterQuery], host, body.len(), body
+    );
+    let request = Secret::new(request);
+
+    let stream = connect(host, port)
+        .unwrap_or_else(|e| panic!("{}: Couldn't connect to token endpoint: {e}", endpoint));
+
+    let mut response = Vec::new();
+    let result = match url.scheme() {
+        "https" => {
+            let connector = native_tls::TlsConnector::new().unwrap();
+            let mut stream = connector.connect(host, stream)
+                .unwrap_or_else(|e| panic!("{}: TLS handshake with token endpoint failed: {e}", endpoint));
+
+            stream.write_all(request.expose().as_bytes()).and_then(|_| stream.read_to_end(&mut response))
+        }
+        "http" => {
+            let mut stream = stream;
+            stream.write_all(request.expose().as_bytes()).and_then(|_| stream.read_to_end(&mut response))
+        }
+        scheme => panic!("{}: Unsupported scheme {}.", endpoint, scheme),
+    };
+
+    result.unwrap_or_else(|e| match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => panic!("{}: Token endpoint didn't respond within {}s.", endpoint, TIMEOUT.as_secs()),
+        _ => panic!("{}: Couldn't talk to token endpoint: {e}", endpoint),
+    });
+    parse_response(&response).unwrap_or_else(|| panic!("{}: Malformed HTTP response.", endpoint))
+}
+
+/// Connects to the first address of `host` that answers within `TIMEOUT`.
+fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(TIMEOUT))?;
+                stream.set_write_timeout(Some(TIMEOUT))?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
+}
+
+fn parse_response(response: &[u8]) -> Option<(u16, String)> {
+    let split = response.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head = std::str::from_utf8(&response[..split]).ok()?;
+    let body = &response[split + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+
+    let chunked = lines.any(|line| {
+        let (name, value) = line.split_once(':').unwrap_or((line, ""));
+        name.eq_ignore_ascii_case("transfer-encoding") && value.trim().eq_ignore_ascii_case("chunked")
+    });
+
+    let body = if chunked { decode_chunked(body)? } else { body.to_vec() };
+    Some((status, String::from_utf8(body).ok()?))
+}
+
+fn decode_chunked(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_end + 2..];
+
+        if size == 0 { return Some(decoded) }
+
+        decoded.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::env;
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+
+    /// Answers one request with each of `responses` on a local port, and
+    /// returns the server config pointing at it, and the requests it got.
+    fn token_endpoint(responses: &[&'static str]) -> (ServerConfig, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let responses = responses.to_vec();
+
+        let server = thread::spawn(move || responses.into_iter().map(|body| {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+
+            // The request is complete once the body is as long as promised.
+            while !String::from_utf8_lossy(&request).split_once("\r\n\r\n").is_some_and(|(head, body)| {
+                head.lines().any(|line| line == format!("Content-Length: {}", body.len()))
+            }) {
+                let read = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+
+            let status = if body.contains("\"error\"") { "400 Bad Request" } else { "200 OK" };
+            write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", status, body.len(), body).unwrap();
+            String::from_utf8(request).unwrap()
+        }).collect());
+
+        let config = format!(
+            "hostname = \"smtp.example.com\"\nport = 587\nusername = \"alice\"\nauth = \"oauth2\"\nclient_id = \"cid\"\ntoken_endpoint = \"http://127.0.0.1:{}/token\"",
+            port
+        );
+
+        (toml::from_str(&config).unwrap(), server)
+    }
+
+    fn cache_file(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("sendmail-test-{}-{}.toml", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn refreshes_and_caches_tokens() {
+        let (server, endpoint) = token_endpoint(&["{\"access_token\":\"fresh\",\"expires_in\":3600}"]);
+        let cache = cache_file("cached");
+        let refreshes = Cell::new(0);
+        let refresh_token = || { refreshes.set(refreshes.get() + 1); Secret::new("good".to_string()) };
+
+        assert_eq!(cached_access_token(&cache, &server, refresh_token).expose(), "fresh");
+        // The endpoint only answers once, so this has to come from the cache.
+        assert_eq!(cached_access_token(&cache, &server, refresh_token).expose(), "fresh");
+        assert_eq!(refreshes.get(), 1);
+
+        let requests = endpoint.join().unwrap();
+        assert!(requests[0].starts_with("POST /token HTTP/1.1\r\n"));
+        assert!(requests[0].ends_with("\r\n\r\ngrant_type=refresh_token&refresh_token=good&client_id=cid"));
+
+        let cached: CachedToken = toml::from_str(&fs::read_to_string(&cache).unwrap()).unwrap();
+        assert_eq!(cached.access_token, "fresh");
+        assert!((now() + 3599..=now() + 3600).contains(&cached.expires_at));
+        fs::remove_file(cache).unwrap();
+    }
+
+    #[test]
+    fn refreshes_tokens_that_are_about_to_expire() {
+        let (server, endpoint) = token_endpoint(&[
+            "{\"access_token\":\"first\",\"expires_in\":30}",
+            "{\"access_token\":\"second\",\"expires_in\":3600}",
+        ]);
+        let cache = cache_file("expiring");
+        let refresh_token = || Secret::new("good".to_string());
+
+        // 30 seconds is within `EXPIRY_MARGIN`, so the first token isn't used again.
+        assert_eq!(cached_access_token(&cache, &server, refresh_token).expose(), "first");
+        assert_eq!(cached_access_token(&cache, &server, refresh_token).expose(), "second");
+        assert_eq!(cached_access_token(&cache, &server, refresh_token).expose(), "second");
+
+        assert_eq!(endpoint.join().unwrap().len(), 2);
+        fs::remove_file(cache).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Couldn't refresh access token: invalid_grant (Token has been revoked.)")]
+    fn reports_endpoint_errors() {
+        let (server, _) = token_endpoint(&["{\"error\":\"invalid_grant\",\"error_description\":\"Token has been revoked.\"}"]);
+        cached_access_token(&cache_file("revoked"), &server, || Secret::new("bad".to_string()));
+    }
+
+    #[test]
+    fn parses_plain_responses() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{}";
+        assert_eq!(parse_response(response), Some((200, "{}".to_string())));
+
+        let response = b"HTTP/1.0 400 Bad Request\r\n\r\n{\"error\":\"invalid_grant\"}";
+        assert_eq!(parse_response(response), Some((400, "{\"error\":\"invalid_grant\"}".to_string())));
+    }
+
+    #[test]
+    fn parses_chunked_responses() {
+        let response = b"HTTP/1.1 200 OK\r\ntransfer-encoding: Chunked\r\n\r\n4\r\n{\"a\"\r\nA;name=value\r\n:\"0123456\"\r\n1\r\n}\r\n0\r\n\r\n";
+        assert_eq!(parse_response(response), Some((200, "{\"a\":\"0123456\"}".to_string())));
+    }
+
+    #[test]
+    fn rejects_malformed_responses() {
+        assert_eq!(parse_response(b"HTTP/1.1 200 OK\r\n"), None);
+        assert_eq!(parse_response(b"HTTP/1.1 OK\r\n\r\n{}"), None);
+        assert_eq!(parse_response(b"HTTP/1.1 200 OK\r\n\r\n\xff"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        // Cut off in the middle of a chunk, or before the last one.
+        assert_eq!(decode_chunked(b"5\r\nab"), None);
+        assert_eq!(decode_chunked(b"2\r\nab\r\n"), None);
+        assert_eq!(decode_chunked(b"zz\r\nab\r\n0\r\n\r\n"), None);
+        assert_eq!(decode_chunked(b"ffffffffffffffffffff\r\nab\r\n"), None);
+        assert_eq!(decode_chunked(b"0\r\n\r\n"), Some(Vec::new()));
+    }
+}
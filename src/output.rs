@@ -0,0 +1,321 @@
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use lettre::{FileTransport, Message, Transport};
+use quoted_printable::ParseMode;
+
+/// Writes the message exactly as it would be sent to stdout. With `decode`,
+/// text parts and encoded headers are decoded so they can be read.
+pub fn print(mail: &Message, decode: bool) {
+    let formatted = mail.formatted();
+    let output = if decode { decode_message(&String::from_utf8_lossy(&formatted)).into_bytes() } else { formatted };
+
+    io::stdout().write_all(&output).expect("Couldn't write to stdout.");
+}
+
+/// Writes `mail` to `target` instead of sending it, and reports where it went
+/// and which envelope would have been used. `target` is either a Maildir, in
+/// which case the message is delivered to its `new/` directory, or a directory
+/// to write a `.eml` file to. Defaults to the temporary directory.
+pub fn dry_run(mail: &Message, target: Option<PathBuf>) {
+    let target = target.unwrap_or_else(env::temp_dir);
+
+    if !target.is_dir() {
+        panic!("{}: Not a directory.", target.display())
+    }
+
+    let path = if is_maildir(&target) {
+        deliver_to_maildir(&target, &mail.formatted())
+    } else {
+        let id = FileTransport::new(&target).send(mail)
+            .unwrap_or_else(|e| panic!("{}: Couldn't write message: {e}", target.display()));
+
+        target.join(format!("{}.eml", id))
+    };
+
+    let envelope = mail.envelope();
+    let from = envelope.from().map(ToString::to_string).unwrap_or_default();
+    let to: Vec<String> = envelope.to().iter().map(ToString::to_string).collect();
+
+    println!("Wrote {}", path.display());
+    println!("  MAIL FROM: <{}>", from);
+    for recipient in to {
+        println!("  RCPT TO:   <{}>", recipient);
+    }
+}
+
+fn is_maildir(path: &Path) -> bool {
+    ["cur", "new", "tmp"].iter().all(|directory| path.join(directory).is_dir())
+}
+
+/// Writes to `tmp/` first and then moves it to `new/`, so mail clients never
+/// see a partial message.
+fn deliver_to_maildir(maildir: &Path, message: &[u8]) -> PathBuf {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let name = format!("{}.M{}P{}.{}", now.as_secs(), now.subsec_micros(), process::id(), hostname());
+
+    let temporary = maildir.join("tmp").join(&name);
+    let path = maildir.join("new").join(&name);
+
+    fs::write(&temporary, message)
+        .unwrap_or_else(|e| panic!("{}: Couldn't write message: {e}", temporary.display()));
+    fs::rename(&temporary, &path)
+        .unwrap_or_else(|e| panic!("{}: Couldn't move message: {e}", path.display()));
+
+    path
+}
+
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    let length = match unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } {
+        0 => buffer.iter().position(|byte| *byte == 0).unwrap_or(buffer.len()),
+        _ => 0,
+    };
+
+    // `/` and `:` have special meanings in Maildir file names.
+    match String::from_utf8_lossy(&buffer[..length]).replace('/', "\\057").replace(':', "\\072") {
+        name if name.is_empty() => "localhost".to_string(),
+        name => name,
+    }
+}
+
+/// Decodes the headers and text parts of a formatted message, leaving its
+/// structure intact. Attachments that aren't text stay encoded.
+fn decode_message(message: &str) -> String {
+    let mut output = String::new();
+    let mut boundaries: Vec<String> = Vec::new();
+
+    let mut in_headers = true;
+    let mut headers: Vec<String> = Vec::new();
+    let mut body: Vec<&str> = Vec::new();
+    let mut encoding = None;
+
+    for line in message.lines() {
+        if in_headers {
+            if !line.is_empty() {
+                match headers.last_mut() {
+                    Some(header) if line.starts_with([' ', '\t']) => header.push_str(line),
+                    _ => headers.push(line.to_string()),
+                }
+                continue;
+            }
+
+            let (content_type, transfer_encoding) = (header(&headers, "content-type"), header(&headers, "content-transfer-encoding"));
+            let is_text = content_type.as_deref().is_none_or(|value| value.to_ascii_lowercase().starts_with("text/"));
+
+            boundaries.extend(content_type.as_deref().and_then(boundary));
+            encoding = transfer_encoding.map(|value| value.to_ascii_lowercase())
+                .filter(|value| is_text && (value == "base64" || value == "quoted-printable"));
+
+            for header in headers.drain(..) {
+                match header.split_once(':') {
+                    Some((name, _)) if encoding.is_some() && name.eq_ignore_ascii_case("content-transfer-encoding") => {
+                        output += &format!("{}: 8bit\n", name)
+                    }
+                    _ => output += &format!("{}\n", decode_words(&header)),
+                }
+            }
+
+            output.push('\n');
+            in_headers = false;
+            continue;
+        }
+
+        let delimiter = boundaries.iter().position(|boundary| {
+            line.strip_prefix("--").and_then(|rest| rest.strip_prefix(boundary.as_str()))
+                .is_some_and(|rest| rest.is_empty() || rest == "--")
+        });
+
+        let Some(index) = delimiter else {
+            body.push(line);
+            continue;
+        };
+
+        output += &decode_body(&body, encoding.as_deref());
+        output += &format!("{}\n", line);
+        body.clear();
+
+        if line.ends_with("--") {
+            // The end of this multipart, anything up to the next delimiter is an epilogue.
+            boundaries.truncate(index);
+            encoding = None;
+        } else {
+            boundaries.truncate(index + 1);
+            in_headers = true;
+        }
+    }
+
+    output += &decode_body(&body, encoding.as_deref());
+    output
+}
+
+fn decode_body(lines: &[&str], encoding: Option<&str>) -> String {
+    let decoded = match encoding {
+        Some("base64") => STANDARD.decode(lines.concat()).ok(),
+        Some("quoted-printable") => quoted_printable::decode(lines.join("\r\n"), ParseMode::Robust).ok(),
+        _ => None,
+    };
+
+    let Some(decoded) = decoded else {
+        return lines.iter().map(|line| format!("{}\n", line)).collect();
+    };
+
+    // The line break before a delimiter belongs to the delimiter, not the text.
+    String::from_utf8_lossy(&decoded).replace("\r\n", "\n") + "\n"
+}
+
+/// The value of the first header called `name`.
+fn header(headers: &[String], name: &str) -> Option<String> {
+    headers.iter()
+        .filter_map(|header| header.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+fn boundary(content_type: &str) -> Option<String> {
+    let (_, rest) = content_type.split_once("boundary=")?;
+    let value = match rest.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next()?,
+        None => rest.split(';').next()?.trim(),
+    };
+
+    Some(value.to_string())
+}
+
+/// Decodes RFC 2047 encoded words like `=?utf-8?b?...?=`. The whitespace
+/// between two encoded words doesn't belong to the text.
+fn decode_words(header: &str) -> String {
+    let mut output = String::new();
+    let mut rest = header;
+    let mut after_word = false;
+
+    while let Some(start) = rest.find("=?") {
+        let (before, candidate) = rest.split_at(start);
+
+        match decode_word(candidate) {
+            Some((decoded, length)) => {
+                if !(after_word && before.trim().is_empty()) { output += before }
+                output += &decoded;
+                rest = &candidate[length..];
+                after_word = true;
+            }
+            None => {
+                output += before;
+                output += "=?";
+                rest = &candidate[2..];
+                after_word = false;
+            }
+        }
+    }
+
+    output + rest
+}
+
+/// Decodes the encoded word at the start of `text`, returning it and its length.
+fn decode_word(text: &str) -> Option<(String, usize)> {
+    let mut parts = text.strip_prefix("=?")?.splitn(3, '?');
+    let (_charset, encoding, rest) = (parts.next()?, parts.next()?, parts.next()?);
+    let encoded = &rest[..rest.find("?=")?];
+
+    let bytes = match encoding {
+        "b" | "B" => STANDARD.decode(encoded).ok()?,
+        "q" | "Q" => quoted_printable::decode(encoded.replace('_', " "), ParseMode::Robust).ok()?,
+        _ => return None,
+    };
+
+    let length = text.len() - rest.len() + encoded.len() + 2;
+    Some((String::from_utf8_lossy(&bytes).into_owned(), length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_encoded_words() {
+        assert_eq!(decode_words("Subject: =?utf-8?b?Q2Fmw6k=?="), "Subject: Café");
+        assert_eq!(decode_words("Subject: =?UTF-8?Q?Caf=C3=A9_au_lait?="), "Subject: Café au lait");
+        // Whitespace between encoded words goes, around them it stays.
+        assert_eq!(decode_words("To: =?utf-8?q?Ro?= =?utf-8?q?bin?= <robin@example.com>"), "To: Robin <robin@example.com>");
+    }
+
+    #[test]
+    fn leaves_other_text_alone() {
+        assert_eq!(decode_words("Subject: 1 =? 2"), "Subject: 1 =? 2");
+        assert_eq!(decode_words("Subject: =?utf-8?x?abc?="), "Subject: =?utf-8?x?abc?=");
+        assert_eq!(decode_words("Subject: =?utf-8?b?unterminated"), "Subject: =?utf-8?b?unterminated");
+    }
+
+    #[test]
+    fn finds_boundaries() {
+        assert_eq!(boundary("multipart/mixed; boundary=\"abc def\"").as_deref(), Some("abc def"));
+        assert_eq!(boundary("multipart/mixed; boundary=abc; charset=utf-8").as_deref(), Some("abc"));
+        assert_eq!(boundary("text/plain"), None);
+    }
+
+    #[test]
+    fn decodes_text_parts_but_not_attachments() {
+        let message = "\
+Subject: =?utf-8?b?Q2Fmw6k=?=
+Content-Type: multipart/mixed; boundary=\"outer\"
+
+--outer
+Content-Type: text/plain; charset=utf-8
+Content-Transfer-Encoding: quoted-printable
+
+Caf=C3=A9 =
+au lait
+--outer
+Content-Type: application/pdf
+Content-Transfer-Encoding: base64
+
+JVBERi0=
+--outer--
+";
+
+        let expected = "\
+Subject: Café
+Content-Type: multipart/mixed; boundary=\"outer\"
+
+--outer
+Content-Type: text/plain; charset=utf-8
+Content-Transfer-Encoding: 8bit
+
+Café au lait
+--outer
+Content-Type: application/pdf
+Content-Transfer-Encoding: base64
+
+JVBERi0=
+--outer--
+";
+
+        assert_eq!(decode_message(message), expected);
+    }
+
+    #[test]
+    fn decodes_nested_multiparts() {
+        let message = "\
+Content-Type: multipart/mixed; boundary=\"outer\"
+
+--outer
+Content-Type: multipart/alternative; boundary=\"inner\"
+
+--inner
+Content-Type: text/plain
+Content-Transfer-Encoding: base64
+
+aMOpbGxv
+--inner--
+--outer--
+";
+
+        assert!(decode_message(message).contains("Content-Transfer-Encoding: 8bit\n\nhéllo\n--inner--\n--outer--\n"));
+    }
+}
@@ -0,0 +1,197 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::process::{Command, Stdio};
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
+
+use crate::config::{self, AuthMethod, PasswordSource, ServerConfig};
+use crate::{agent, authinfo, keyring};
+
+/// A password that is wiped from memory when dropped.
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(secret: String) -> Self {
+        Secret(secret)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// For reading a secret in place, so no copy of it is left behind.
+    pub fn as_mut_string(&mut self) -> &mut String {
+        &mut self.0
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        // SAFETY: zeroes are valid UTF-8, so the string stays valid.
+        for byte in unsafe { self.0.as_bytes_mut() } {
+            unsafe { ptr::write_volatile(byte, 0) };
+        }
+
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+/// Looks up the SMTP password, trying (in order) `--password`, `--password-stdin`,
+/// `SENDMAIL_PASSWORD_<ACCOUNT>`, the agent, `password_command` or
+/// `password_source` and finally an interactive prompt. Passwords from the
+/// last three are handed to the agent, if it's running.
+pub fn get_password(password: Option<Secret>, from_stdin: bool, account: &str, server: &ServerConfig) -> Secret {
+    if let Some(password) = password {
+        return password;
+    }
+
+    if from_stdin {
+        return read_first_line(&mut io::stdin().lock())
+            .unwrap_or_else(|| panic!("stdin: Didn't receive a password."));
+    }
+
+    if let Ok(password) = env::var(env_variable(account)) {
+        return Secret::new(password);
+    }
+
+    if let Some(password) = agent::get(account) {
+        return password;
+    }
+
+    let password = lookup_password(account, server);
+    agent::put(account, &password);
+    password
+}
+
+fn lookup_password(account: &str, server: &ServerConfig) -> Secret {
+    if let Some(command) = &server.password_command {
+        return run_password_command(command);
+    }
+
+    match server.password_source {
+        Some(PasswordSource::Keyring) => match keyring::lookup(account) {
+            Some(password) => return password,
+            None => eprintln!("No password for {} in the keyring, store one with `sendmail password set {}`.", account, account),
+        },
+        Some(PasswordSource::Netrc) => match authinfo::lookup(server) {
+            Some(password) => return password,
+            None => {
+                let files: Vec<String> = authinfo::files().iter().map(|path| path.display().to_string()).collect();
+                let username = server.username.as_deref().unwrap_or_default();
+                eprintln!("No entry for {}@{}:{} in {}.", username, server.hostname, server.port, files.join(", "));
+            }
+        },
+        None => {}
+    }
+
+    prompt(&prompt_message(server))
+}
+
+/// Reads a new password to store, from stdin or by asking twice on the terminal.
+pub fn read_new_password(from_stdin: bool, server: &ServerConfig) -> Secret {
+    if from_stdin {
+        return read_first_line(&mut io::stdin().lock())
+            .unwrap_or_else(|| panic!("stdin: Didn't receive a password."));
+    }
+
+    let password = prompt(&prompt_message(server));
+    let repeated = prompt("Repeat to confirm: ");
+
+    if password.expose() != repeated.expose() { panic!("Passwords don't match.") }
+    password
+}
+
+fn prompt_message(server: &ServerConfig) -> String {
+    let what = match server.auth {
+        AuthMethod::Password => "Password",
+        AuthMethod::OAuth2 => "Refresh token",
+    };
+
+    let username = server.username.as_deref().unwrap_or_default();
+    format!("{} for {}@{}: ", what, username, server.hostname)
+}
+
+/// Name of the environment variable holding the password for `account`, e.g.
+/// `SENDMAIL_PASSWORD_SCHOOL`.
+fn env_variable(account: &str) -> String {
+    format!("SENDMAIL_PASSWORD_{}", config::env_name(account))
+}
+
+fn run_password_command(command: &str) -> Secret {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stderr(Stdio::inherit())
+        .output()
+        .unwrap_or_else(|e| panic!("{}: Couldn't run password command: {e}", command));
+
+    if !output.status.success() {
+        panic!("{}: Password command failed ({}).", command, output.status)
+    }
+
+    let stdout = Secret::new(String::from_utf8(output.stdout)
+        .unwrap_or_else(|_| panic!("{}: Password command didn't output valid UTF-8.", command)));
+
+    match stdout.expose().lines().next() {
+        Some(line) if !line.is_empty() => Secret::new(line.to_string()),
+        _ => panic!("{}: Password command didn't output a password.", command),
+    }
+}
+
+/// Asks for the password on the controlling terminal, without echoing it.
+fn prompt(message: &str) -> Secret {
+    let tty = File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .unwrap_or_else(|_| panic!("No password given and no terminal to ask for one. Pass --password-stdin, set SENDMAIL_PASSWORD_<ACCOUNT> or set `password_command` in the account config."));
+
+    (&tty).write_all(message.as_bytes()).expect("Couldn't write to terminal.");
+
+    let password = {
+        let _echo = EchoGuard::disable(&tty);
+        read_first_line(&mut BufReader::new(&tty))
+    };
+
+    (&tty).write_all(b"\n").expect("Couldn't write to terminal.");
+    password.unwrap_or_else(|| panic!("No password entered."))
+}
+
+fn read_first_line(reader: &mut impl BufRead) -> Option<Secret> {
+    let mut line = Secret::new(String::new());
+    reader.read_line(&mut line.0).expect("Couldn't read password.");
+
+    let password = line.expose().trim_end_matches(['\r', '\n']);
+    if password.is_empty() { return None }
+
+    Some(Secret::new(password.to_string()))
+}
+
+/// Turns off terminal echo until dropped.
+struct EchoGuard<'a> {
+    tty: &'a File,
+    original: libc::termios,
+}
+
+impl<'a> EchoGuard<'a> {
+    fn disable(tty: &'a File) -> Self {
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(tty.as_raw_fd(), &mut termios) } != 0 {
+            panic!("Couldn't read terminal settings.")
+        }
+
+        let original = termios;
+        termios.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(tty.as_raw_fd(), libc::TCSANOW, &termios) };
+
+        EchoGuard { tty, original }
+    }
+}
+
+impl Drop for EchoGuard<'_> {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(self.tty.as_raw_fd(), libc::TCSANOW, &self.original) };
+    }
+}
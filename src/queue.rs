@@ -0,0 +1,221 @@
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::crate_name;
+use lettre::address::Envelope;
+use lettre::Address;
+use platform_dirs::AppDirs;
+use serde::{Deserialize, Serialize};
+
+use crate::time;
+
+/// How long a message can be claimed for sending before it's considered
+/// abandoned and put back. Well over what retries and sending limits can take.
+const ABANDONED_CLAIM: Duration = Duration::from_secs(60 * 60);
+
+/// A message waiting in the outbox. The message itself is stored next to it,
+/// exactly as it would have been sent.
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    /// The account chosen when the message was written.
+    pub account: String,
+    pub from: Option<String>,
+    pub to: Vec<String>,
+    pub queued_at: u64,
+    /// When to send the message, if it was scheduled with `--at` or `--in`.
+    pub send_at: Option<u64>,
+    #[serde(default)]
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// Set when the server rejected the message, so it isn't tried again until
+    /// it's flushed by ID.
+    #[serde(default)]
+    pub held: bool,
+}
+
+impl Entry {
+    /// Whether the message should be sent by now.
+    pub fn is_due(&self) -> bool {
+        !self.held && self.send_at.is_none_or(|send_at| send_at <= time::now())
+    }
+
+    pub fn envelope(&self) -> Envelope {
+        let parse = |address: &String| address.parse::<Address>()
+            .unwrap_or_else(|_| panic!("Malformed address in queue: {}", address));
+
+        Envelope::new(self.from.as_ref().map(parse), self.to.iter().map(parse).collect())
+            .unwrap_or_else(|e| panic!("Invalid envelope in queue: {e}"))
+    }
+}
+
+/// Where queued messages are kept: `queue/` in the data directory.
+pub fn directory() -> PathBuf {
+    let directories = AppDirs::new(Some(crate_name!()), false).unwrap();
+    directories.data_dir.join("queue")
+}
+
+/// Stores a message for `account`, to be sent at `send_at` or whenever the
+/// queue is flushed, and returns its ID.
+pub fn add(account: &str, envelope: &Envelope, message: &[u8], send_at: Option<u64>) -> String {
+    let directory = directory();
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(&directory)
+        .unwrap_or_else(|e| panic!("{}: Couldn't create queue directory: {e}", directory.display()));
+
+    let entry = Entry {
+        account: account.to_string(),
+        from: envelope.from().map(ToString::to_string),
+        to: envelope.to().iter().map(ToString::to_string).collect(),
+        queued_at: time::now(),
+        send_at,
+        attempts: 0,
+        last_error: None,
+        held: false,
+    };
+
+    // Millisecond timestamps keep the queue in order, and are short enough to type.
+    // Creating the message file claims the ID, so two processes queueing at
+    // the same time don't overwrite each other's message.
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    let (id, mut file) = (now..)
+        .map(|id| id.to_string())
+        .find_map(|id| match open_private(&message_file(&id), true) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => None,
+            result => Some(result.map(|file| (id, file))),
+        })
+        .unwrap()
+        .unwrap_or_else(|e| panic!("{}: Couldn't write to queue: {e}", directory.display()));
+
+    // The message goes first, so an entry is never listed without one.
+    file.write_all(message)
+        .unwrap_or_else(|e| panic!("{}: Couldn't write to queue: {e}", message_file(&id).display()));
+    update(&id, &entry);
+
+    id
+}
+
+/// All queued messages, oldest first. Messages that are being sent aren't
+/// included.
+pub fn list() -> Vec<(String, Entry)> {
+    restore_abandoned();
+    let Ok(files) = fs::read_dir(directory()) else { return Vec::new() };
+
+    let mut entries: Vec<(String, Entry)> = files
+        .flatten()
+        .map(|file| file.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "toml"))
+        .filter_map(|path| {
+            let id = path.file_stem()?.to_str()?.to_string();
+            let entry = fs::read_to_string(&path).ok().and_then(|contents| toml::from_str(&contents).ok());
+
+            if entry.is_none() { eprintln!("{}: Couldn't read queue entry, skipping it.", path.display()) }
+            Some((id, entry?))
+        })
+        .collect();
+
+    entries.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then(a.cmp(b)));
+    entries
+}
+
+pub fn message(id: &str) -> Vec<u8> {
+    let path = message_file(id);
+    fs::read(&path).unwrap_or_else(|e| panic!("{}: Couldn't read queued message: {e}", path.display()))
+}
+
+pub fn update(id: &str, entry: &Entry) {
+    write_private(&entry_file(id), toml::to_string(entry).unwrap().as_bytes());
+}
+
+/// Takes a message out of the queue to send it, so nobody else sends it at
+/// the same time. Returns its entry, or nothing when someone else got to it
+/// first. Put it back with `release`, or drop it with `finish`.
+pub fn claim(id: &str) -> Option<Entry> {
+    fs::rename(entry_file(id), claimed_file(id)).ok()?;
+
+    // Renaming keeps the old time, but how long it's been claimed is what counts.
+    let _ = fs::File::options().write(true).open(claimed_file(id)).and_then(|file| file.set_modified(SystemTime::now()));
+
+    let entry = fs::read_to_string(claimed_file(id)).ok().and_then(|contents| toml::from_str(&contents).ok());
+    if entry.is_none() {
+        eprintln!("{}: Couldn't read queue entry, skipping it.", claimed_file(id).display());
+        let _ = fs::rename(claimed_file(id), entry_file(id));
+    }
+
+    entry
+}
+
+/// Puts a claimed message back in the queue, after sending it failed.
+pub fn release(id: &str, entry: &Entry) {
+    write_private(&claimed_file(id), toml::to_string(entry).unwrap().as_bytes());
+    fs::rename(claimed_file(id), entry_file(id))
+        .unwrap_or_else(|e| panic!("{}: Couldn't put message back in the queue: {e}", entry_file(id).display()));
+}
+
+/// Drops a claimed message, after it was sent.
+pub fn finish(id: &str) {
+    let _ = fs::remove_file(claimed_file(id));
+    let _ = fs::remove_file(message_file(id));
+}
+
+pub fn contains(id: &str) -> bool {
+    entry_file(id).exists()
+}
+
+/// Removes a message from the queue. Returns whether it was there, and so
+/// whether we were the ones to take it: when two processes race to remove the
+/// same message, only one of them succeeds.
+pub fn remove(id: &str) -> bool {
+    if fs::remove_file(entry_file(id)).is_err() { return false }
+
+    let _ = fs::remove_file(message_file(id));
+    true
+}
+
+/// Puts back messages that were claimed long enough ago that whoever claimed
+/// them must have died while sending.
+fn restore_abandoned() {
+    let Ok(files) = fs::read_dir(directory()) else { return };
+
+    for path in files.flatten().map(|file| file.path()) {
+        if path.extension().is_none_or(|extension| extension != "sending") { continue }
+
+        let claimed_at = fs::metadata(&path).and_then(|metadata| metadata.modified()).unwrap_or_else(|_| SystemTime::now());
+        if claimed_at.elapsed().unwrap_or_default() < ABANDONED_CLAIM { continue }
+
+        let _ = fs::rename(&path, path.with_extension("toml"));
+    }
+}
+
+fn entry_file(id: &str) -> PathBuf {
+    directory().join(format!("{}.toml", id))
+}
+
+fn claimed_file(id: &str) -> PathBuf {
+    directory().join(format!("{}.sending", id))
+}
+
+fn message_file(id: &str) -> PathBuf {
+    directory().join(format!("{}.eml", id))
+}
+
+fn write_private(path: &Path, contents: &[u8]) {
+    open_private(path, false)
+        .and_then(|mut file| file.write_all(contents))
+        .unwrap_or_else(|e| panic!("{}: Couldn't write to queue: {e}", path.display()));
+}
+
+/// Messages can contain anything, so only the user gets to read them. With
+/// `new`, fails if the file already exists, rather than overwriting it.
+fn open_private(path: &Path, new: bool) -> io::Result<fs::File> {
+    let mut options = fs::File::options();
+    options.write(true).mode(0o600);
+
+    match new {
+        true => options.create_new(true),
+        false => options.create(true).truncate(true),
+    };
+
+    options.open(path)
+}
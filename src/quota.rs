@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use clap::crate_name;
+use platform_dirs::AppDirs;
+
+use crate::config::Config;
+use crate::time;
+
+const MINUTE: u64 = 60;
+const DAY: u64 = 24 * 60 * 60;
+
+/// How many emails an account sent recently, counted over the last minute
+/// and the last 24 hours, the way providers count them.
+pub struct Usage {
+    sent: Vec<u64>,
+}
+
+impl Usage {
+    pub fn last_minute(&self) -> usize {
+        self.since(time::now().saturating_sub(MINUTE))
+    }
+
+    pub fn last_day(&self) -> usize {
+        self.since(time::now().saturating_sub(DAY))
+    }
+
+    /// When the oldest send in the last `window` seconds stops counting.
+    fn frees_up(&self, window: u64) -> u64 {
+        let start = time::now().saturating_sub(window);
+        self.sent.iter().find(|sent| **sent > start).map_or(0, |sent| sent + window)
+    }
+
+    fn since(&self, start: u64) -> usize {
+        self.sent.iter().filter(|sent| **sent > start).count()
+    }
+}
+
+/// Where sends are counted: `sent.toml` in the data directory, with the
+/// times of the last day's sends for each account.
+pub fn path() -> PathBuf {
+    let directories = AppDirs::new(Some(crate_name!()), false).unwrap();
+    directories.data_dir.join("sent.toml")
+}
+
+pub fn usage(account: &str) -> Usage {
+    let mut sent = read().remove(account).unwrap_or_default();
+    sent.sort();
+
+    Usage { sent }
+}
+
+/// Makes sure sending one more email stays within the limits of `account`.
+/// Waits when that's a matter of seconds, for `max_per_minute`, and fails when
+/// it's not, for `max_per_day`.
+pub fn wait_for_turn(account: &str, config: &Config) -> Result<(), String> {
+    let usage = usage(account);
+
+    if let Some(limit) = config.max_per_day.filter(|limit| usage.last_day() >= *limit as usize) {
+        return Err(format!(
+            "Reached the limit of {} emails per day, try again after {}.",
+            limit, time::format_time(usage.frees_up(DAY))
+        ));
+    }
+
+    if let Some(limit) = config.max_per_minute.filter(|limit| usage.last_minute() >= *limit as usize) {
+        let wait = usage.frees_up(MINUTE).saturating_sub(time::now()).max(1);
+        eprintln!("Reached the limit of {} emails per minute, waiting {}s...", limit, wait);
+        thread::sleep(Duration::from_secs(wait));
+    }
+
+    Ok(())
+}
+
+/// Counts an email sent by `account`.
+pub fn record(account: &str) {
+    let path = path();
+    let directory = path.parent().unwrap();
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(directory)
+        .unwrap_or_else(|e| panic!("{}: Couldn't create directory: {e}", directory.display()));
+
+    // Held until the new counts are in place, so a `queue run` next to a merge
+    // doesn't lose the other's sends.
+    let lock = path.with_extension("lock");
+    let lock = fs::File::create(&lock).and_then(|file| file.lock().map(|_| file))
+        .unwrap_or_else(|e| panic!("{}: Couldn't lock the count of sent emails: {e}", lock.display()));
+
+    let mut accounts = read();
+    let day_ago = time::now().saturating_sub(DAY);
+
+    accounts.entry(account.to_string()).or_default().push(time::now());
+    // Anything older than a day doesn't count towards any limit.
+    accounts.values_mut().for_each(|sent| sent.retain(|sent| *sent > day_ago));
+    accounts.retain(|_, sent| !sent.is_empty());
+
+    // Written next to it and then moved in place, so it's never read half-written.
+    let temporary = path.with_extension(format!("toml.{}", process::id()));
+    fs::write(&temporary, toml::to_string(&accounts).unwrap())
+        .and_then(|_| fs::rename(&temporary, &path))
+        .unwrap_or_else(|e| panic!("{}: Couldn't count sent email: {e}", path.display()));
+
+    drop(lock);
+}
+
+fn read() -> BTreeMap<String, Vec<u64>> {
+    let path = path();
+    let Ok(contents) = fs::read_to_string(&path) else { return BTreeMap::new() };
+
+    toml::from_str(&contents).unwrap_or_else(|_| {
+        eprintln!("{}: Couldn't read the count of sent emails, starting over.", path.display());
+        BTreeMap::new()
+    })
+}
@@ -0,0 +1,224 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The longest duration `parse_duration` accepts. Far more than anyone waits
+/// for an email, and small enough to add to any time without overflowing.
+pub const MAX_DURATION: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Parses durations like `30s`, `15m`, `2h`, `1d` or `1h30m`. A number without
+/// a unit is in seconds. Durations go up to `MAX_DURATION`.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration `{}`, expected something like `30s`, `15m`, `2h` or `1h30m`.", text);
+    let limited = |seconds: u64| match Duration::from_secs(seconds) {
+        duration if duration <= MAX_DURATION => Ok(duration),
+        _ => Err(format!("Duration `{}` is more than 100 years.", text)),
+    };
+
+    if let Ok(seconds) = text.parse::<u64>() {
+        return limited(seconds);
+    }
+
+    let mut seconds: u64 = 0;
+    let mut number = String::new();
+
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return Err(invalid()),
+        };
+
+        let amount = number.parse::<u64>().map_err(|_| invalid())?;
+        seconds = amount.checked_mul(unit).and_then(|amount| seconds.checked_add(amount)).ok_or_else(invalid)?;
+        number.clear();
+    }
+
+    if !number.is_empty() || text.is_empty() { return Err(invalid()) }
+    limited(seconds)
+}
+
+/// Parses a local date and time like `2026-10-16T09:00` or `2026-10-16 09:00:30`,
+/// or just a time like `09:00`, which means the next time it's 09:00. A date
+/// and time has to be in the future.
+pub fn parse_time(text: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid time `{}`, expected something like `2026-10-16T09:00` or `09:00`.", text);
+    let numbers = |part: &str, separator: char| -> Result<Vec<i32>, String> {
+        part.split(separator).map(|number| number.parse().map_err(|_| invalid())).collect()
+    };
+
+    let (date, time) = match text.split_once(['T', ' ']) {
+        Some((date, time)) => (Some(date), time),
+        None => (None, text),
+    };
+
+    let time = numbers(time, ':')?;
+    let (hour, minute, second) = match time[..] {
+        [hour, minute] => (hour, minute, 0),
+        [hour, minute, second] => (hour, minute, second),
+        _ => return Err(invalid()),
+    };
+
+    if !(0..24).contains(&hour) || !(0..60).contains(&minute) || !(0..60).contains(&second) {
+        return Err(invalid());
+    }
+
+    let mut tm = local_time(now());
+    tm.tm_hour = hour;
+    tm.tm_min = minute;
+    tm.tm_sec = second;
+
+    if let Some(date) = date {
+        let [year, month, day] = numbers(date, '-')?[..] else { return Err(invalid()) };
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) { return Err(invalid()) }
+
+        tm.tm_year = year - 1900;
+        tm.tm_mon = month - 1;
+        tm.tm_mday = day;
+    }
+
+    let mut timestamp = from_local_time(tm).ok_or_else(invalid)?;
+
+    // mktime quietly turns February 31st into March, and moves times that
+    // don't exist because of daylight saving time.
+    let result = local_time(timestamp);
+    if (result.tm_year, result.tm_mon, result.tm_mday, result.tm_hour, result.tm_min, result.tm_sec)
+        != (tm.tm_year, tm.tm_mon, tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec)
+    {
+        return Err(format!("`{}` isn't a valid local time.", text));
+    }
+
+    if timestamp <= now() {
+        if date.is_some() { return Err(format!("`{}` is in the past.", text)) }
+
+        // A time without a date that has already passed today means tomorrow.
+        tm.tm_mday += 1;
+        timestamp = from_local_time(tm).ok_or_else(invalid)?;
+    }
+
+    Ok(timestamp)
+}
+
+/// Formats a timestamp as local time, like `2026-10-16 09:00`.
+pub fn format_time(timestamp: u64) -> String {
+    let tm = local_time(timestamp);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday, tm.tm_hour, tm.tm_min)
+}
+
+/// How long ago `timestamp` was, roughly, like `5m` or `2d`.
+pub fn age(timestamp: u64) -> String {
+    let seconds = now().saturating_sub(timestamp);
+
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m", seconds / 60),
+        3600..=86399 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+fn local_time(timestamp: u64) -> libc::tm {
+    let time = timestamp as libc::time_t;
+    let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+    unsafe { libc::localtime_r(&time, &mut tm) };
+    tm
+}
+
+fn from_local_time(mut tm: libc::tm) -> Option<u64> {
+    // Let mktime figure out whether daylight saving time applies.
+    tm.tm_isdst = -1;
+    let timestamp = unsafe { libc::mktime(&mut tm) };
+
+    u64::try_from(timestamp).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("1d2h"), Ok(Duration::from_secs(26 * 60 * 60)));
+        assert_eq!(parse_duration("0s"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for text in ["", "m", "1h30", "1w", "-5m", "1.5h", "h1"] {
+            assert!(parse_duration(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_durations_over_the_limit() {
+        assert_eq!(parse_duration("36500d"), Ok(MAX_DURATION));
+        assert!(parse_duration("36501d").is_err());
+        assert!(parse_duration("213503982334601d").is_err());
+        assert!(parse_duration("18446744073709551615").is_err());
+    }
+
+    #[test]
+    fn rejects_durations_that_overflow() {
+        assert!(parse_duration("99999999999999999999d").is_err());
+        assert!(parse_duration("999999999999999999d").is_err());
+        assert!(parse_duration("18446744073709551615s1s").is_err());
+    }
+
+    #[test]
+    fn parses_dates_and_times() {
+        let timestamp = parse_time("2999-01-31T09:05").unwrap();
+        assert_eq!(format_time(timestamp), "2999-01-31 09:05");
+        assert_eq!(parse_time("2999-01-31 09:05:00"), Ok(timestamp));
+        assert_eq!(parse_time("2999-01-31T09:05:30"), Ok(timestamp + 30));
+    }
+
+    #[test]
+    fn parses_times_as_the_next_occurrence() {
+        let timestamp = parse_time("09:00").unwrap();
+
+        assert!(timestamp > now());
+        assert!(timestamp <= now() + 25 * 60 * 60);
+        assert!(format_time(timestamp).ends_with(" 09:00"));
+    }
+
+    #[test]
+    fn rejects_invalid_times() {
+        for text in ["", "9", "24:00", "09:60", "09:00:60", "2999-13-01T09:00", "2999-01-00T09:00", "2999-01-01", "2999/01/01 09:00"] {
+            assert!(parse_time(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_dates_that_dont_exist() {
+        assert!(parse_time("2999-02-31T09:00").is_err());
+        assert!(parse_time("2999-04-31T09:00").is_err());
+        assert!(parse_time("2996-02-29T09:00").is_ok());
+    }
+
+    #[test]
+    fn rejects_dates_in_the_past() {
+        assert_eq!(parse_time("2020-01-01T09:00"), Err("`2020-01-01T09:00` is in the past.".to_string()));
+    }
+
+    #[test]
+    fn formats_ages() {
+        assert_eq!(age(now()), "0s");
+        assert_eq!(age(now() - 90), "1m");
+        assert_eq!(age(now() - 2 * 60 * 60), "2h");
+        assert_eq!(age(now() - 3 * 24 * 60 * 60), "3d");
+    }
+}
@@ -0,0 +1,258 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use lettre::address::Envelope;
+use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+use lettre::transport::smtp::client::{Certificate, SmtpConnection, Tls, TlsParameters};
+use lettre::transport::smtp::extension::ClientId;
+use lettre::transport::smtp::Error as SmtpError;
+use lettre::Transport;
+use openssl::hash::MessageDigest;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::{X509NameRef, X509Ref, X509};
+
+use crate::config::{ServerConfig, TlsMode};
+
+/// How long to wait for the server when fetching certificates for a report.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for the server while sending, unless `timeout_secs` says
+/// otherwise. The same as lettre's default.
+const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub fn create_tls(server: &ServerConfig) -> Tls {
+    match server.tls_mode() {
+        TlsMode::Implicit => Tls::Wrapper(parameters(server)),
+        TlsMode::Starttls => Tls::Required(parameters(server)),
+        TlsMode::Opportunistic => Tls::Opportunistic(parameters(server)),
+        TlsMode::None => Tls::None,
+    }
+}
+
+fn parameters(server: &ServerConfig) -> TlsParameters {
+    let mut parameters = TlsParameters::builder(server.hostname.clone())
+        .dangerous_accept_invalid_certs(server.danger_accept_invalid_certs);
+
+    if let Some(ca_file) = &server.ca_file {
+        for certificate in read_ca_file(ca_file) {
+            parameters = parameters.add_root_certificate(certificate);
+        }
+    }
+
+    parameters.build()
+        .unwrap_or_else(|e| panic!("{}: Couldn't set up TLS: {e}", server.hostname))
+}
+
+/// Reads every certificate in a PEM bundle.
+fn read_ca_file(path: &str) -> Vec<Certificate> {
+    let pem = fs::read(path).unwrap_or_else(|e| panic!("{}: Couldn't read CA file: {e}", path));
+    let certificates = X509::stack_from_pem(&pem)
+        .unwrap_or_else(|e| panic!("{}: Couldn't parse CA file: {e}", path));
+
+    if certificates.is_empty() { panic!("{}: No certificates in CA file.", path) }
+
+    certificates.iter()
+        .map(|certificate| Certificate::from_der(certificate.to_der().unwrap()).unwrap())
+        .collect()
+}
+
+/// Sends over a connection of its own instead of through lettre's pool, for
+/// servers with a `pin_sha256`. lettre doesn't show us the certificates of the
+/// connections it makes, and checking the pin anywhere else would leave the
+/// connection that carries the password and messages unchecked.
+pub struct PinnedTransport<'a> {
+    server: &'a ServerConfig,
+    tls: Tls,
+    credentials: Option<Credentials>,
+    mechanisms: Vec<Mechanism>,
+    /// The connection to send the next email over. Until the first email is
+    /// sent, this may also be why connecting failed.
+    connection: RefCell<Option<Result<SmtpConnection, SmtpError>>>,
+}
+
+impl<'a> PinnedTransport<'a> {
+    /// Connects right away, so a certificate that doesn't match is caught
+    /// before anything is taken out of the queue.
+    pub fn new(server: &'a ServerConfig, credentials: Option<Credentials>, mechanisms: Vec<Mechanism>) -> Self {
+        let transport = PinnedTransport { server, tls: create_tls(server), credentials, mechanisms, connection: RefCell::new(None) };
+        transport.connection.replace(Some(transport.connect()));
+        transport
+    }
+
+    /// Connects, checks the certificate against the pin, and only then logs in.
+    fn connect(&self) -> Result<SmtpConnection, SmtpError> {
+        let hello = ClientId::default();
+        let timeout = self.server.timeout_secs.map_or(SEND_TIMEOUT, Duration::from_secs);
+
+        let wrapper = match &self.tls {
+            Tls::Wrapper(parameters) => Some(parameters),
+            _ => None,
+        };
+
+        let mut connection = SmtpConnection::connect((self.server.hostname.as_str(), self.server.port), Some(timeout), &hello, wrapper, None)?;
+
+        match &self.tls {
+            Tls::Required(parameters) => connection.starttls(parameters, &hello)?,
+            Tls::Opportunistic(parameters) if connection.can_starttls() => connection.starttls(parameters, &hello)?,
+            _ => {}
+        }
+
+        check_pin(self.server, &connection);
+
+        if let Some(credentials) = &self.credentials {
+            connection.auth(&self.mechanisms, credentials)?;
+        }
+
+        Ok(connection)
+    }
+}
+
+impl Transport for PinnedTransport<'_> {
+    type Ok = ();
+    type Error = SmtpError;
+
+    fn send_raw(&self, envelope: &Envelope, email: &[u8]) -> Result<(), SmtpError> {
+        let mut connection = match self.connection.take() {
+            // The server may have hung up while the connection sat idle.
+            Some(Ok(mut connection)) => match connection.test_connected() {
+                true => connection,
+                false => self.connect()?,
+            },
+            Some(Err(e)) => return Err(e),
+            None => self.connect()?,
+        };
+
+        let result = connection.send(envelope, email);
+
+        // lettre drops the connection on any error, the next email gets a new one.
+        if !connection.has_broken() { self.connection.replace(Some(Ok(connection))); }
+        result.map(|_| ())
+    }
+}
+
+impl Drop for PinnedTransport<'_> {
+    fn drop(&mut self) {
+        if let Some(Ok(mut connection)) = self.connection.take() {
+            let _ = connection.quit();
+        }
+    }
+}
+
+/// Makes sure the certificate `connection` is secured with matches
+/// `pin_sha256`, before anything is sent over it.
+fn check_pin(server: &ServerConfig, connection: &SmtpConnection) {
+    let Some(pin) = &server.pin_sha256 else { return };
+
+    let certificate = connection.peer_certificate()
+        .unwrap_or_else(|e| panic!("{}: Couldn't check certificate pin: {e}\n\n{}", server.hostname, describe_chain(server)));
+
+    let fingerprint = fingerprint(&X509::from_der(&certificate).unwrap());
+
+    if normalize_fingerprint(&fingerprint) != normalize_fingerprint(pin) {
+        panic!(
+            "{}: Certificate doesn't match `pin_sha256`.\n  expected: {}\n  got:      {}\n\n{}",
+            server.hostname, pin, fingerprint, describe_chain(server)
+        )
+    }
+}
+
+/// Strips the colons and casing from a hex fingerprint, so both
+/// `AB:CD:...` and `abcd...` can be used in the config.
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.chars().filter(|c| *c != ':').map(|c| c.to_ascii_lowercase()).collect()
+}
+
+fn fingerprint(certificate: &X509Ref) -> String {
+    let digest = certificate.digest(MessageDigest::sha256()).unwrap();
+    digest.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":")
+}
+
+/// Whether sending failed because the TLS handshake did, which lettre
+/// sometimes reports as a connection error.
+pub fn is_handshake_failure(error: &SmtpError) -> bool {
+    error.is_tls() || error.source().is_some_and(|source| source.is::<native_tls::HandshakeError<TcpStream>>())
+}
+
+/// Describes the certificate chain the server presents and why it's not
+/// trusted, for when the TLS handshake fails.
+pub fn describe_chain(server: &ServerConfig) -> String {
+    match fetch_chain(server) {
+        Ok(report) => report,
+        Err(e) => format!("(Couldn't fetch the certificate chain: {e})"),
+    }
+}
+
+fn fetch_chain(server: &ServerConfig) -> Result<String, Box<dyn Error>> {
+    let mut stream = TcpStream::connect((server.hostname.as_str(), server.port))?;
+    stream.set_read_timeout(Some(REPORT_TIMEOUT))?;
+    stream.set_write_timeout(Some(REPORT_TIMEOUT))?;
+
+    match server.tls_mode() {
+        TlsMode::Implicit => {}
+        TlsMode::Starttls | TlsMode::Opportunistic => starttls(&mut stream)?,
+        TlsMode::None => return Err("TLS is disabled for this server".into()),
+    }
+
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    if let Some(ca_file) = &server.ca_file {
+        builder.set_ca_file(ca_file)?;
+    }
+
+    // Verify, but don't abort, so the chain can be inspected either way.
+    builder.set_verify(SslVerifyMode::NONE);
+    let stream = builder.build().connect(&server.hostname, stream)?;
+
+    let ssl = stream.ssl();
+    let verdict = ssl.verify_result();
+    let mut report = format!("Certificate chain presented by {}:{} ({}):\n", server.hostname, server.port, verdict.error_string());
+
+    for (depth, certificate) in ssl.peer_cert_chain().into_iter().flatten().enumerate() {
+        report += &format!(
+            "  {}: subject:  {}\n     issuer:   {}\n     valid:    {} until {}\n     sha256:   {}\n",
+            depth,
+            describe_name(certificate.subject_name()),
+            describe_name(certificate.issuer_name()),
+            certificate.not_before(),
+            certificate.not_after(),
+            fingerprint(certificate),
+        );
+    }
+
+    Ok(report)
+}
+
+fn describe_name(name: &X509NameRef) -> String {
+    let entries: Vec<String> = name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry.data().as_utf8().map(|value| value.to_string()).unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect();
+
+    entries.join(", ")
+}
+
+/// Just enough SMTP to get to the TLS handshake.
+fn starttls(stream: &mut TcpStream) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut read_reply = |expected: &str| -> Result<(), Box<dyn Error>> {
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 { return Err("connection closed".into()) }
+            if !line.starts_with(expected) { return Err(format!("unexpected reply: {}", line.trim()).into()) }
+            if line.as_bytes().get(3) != Some(&b'-') { return Ok(()) }
+        }
+    };
+
+    read_reply("220")?;
+    stream.write_all(b"EHLO sendmail\r\n")?;
+    read_reply("250")?;
+    stream.write_all(b"STARTTLS\r\n")?;
+    read_reply("220")
+}